@@ -24,6 +24,12 @@ pub struct Code {
     code: Vec<Instruction>
 }
 
+impl Default for Code {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Code {
     pub fn new() -> Code {
         Code { code: vec![] }
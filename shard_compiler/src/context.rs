@@ -18,21 +18,73 @@
 //
 
 use std;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use shard_core::opcodes::{Opcode, OperandKind};
 
 use crate::code::Code;
+use crate::diagnostic::{Diagnostic, Location, Span};
+use crate::disassembler;
 use crate::glob::Glob;
+use crate::instruction::{Instruction, Literal};
+use crate::lexer;
+use crate::expression::Expression;
+use crate::object::{ObjectFile, ObjectSymbol, Relocation};
 use crate::out_bin::OutBin;
+use crate::symbols::{AddressNames, LineMapping, SymbolMap};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Section {
+    Code,
+    // Labels without data are placed in between globals
+    Data,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Glob,
+    Constant,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolKind::Label => write!(f, "label"),
+            SymbolKind::Glob => write!(f, "global"),
+            SymbolKind::Constant => write!(f, "constant"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub location: Location,
+}
 
 pub struct Context {
     code: Code,
     globs: Vec<Glob>,
-    unique_labels: HashSet<String>,
+    // Every label, global and constant with the place it was defined
+    symbols: HashMap<String, Symbol>,
+    string_literal_count: usize,
+    constants: HashMap<String, i64>,
+    section: Section,
+    // Label set with .entry, execution starts at 0x00 without it
+    entry: Option<(String, Option<Span>)>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Context {
-        Context { code: Code::new(), globs: vec![], unique_labels: HashSet::new() }
+        Context { code: Code::new(), globs: vec![], symbols: HashMap::new(), string_literal_count: 0, constants: HashMap::new(), section: Section::Code, entry: None }
     }
 
     pub fn get_code(&self) -> &Code {
@@ -43,46 +95,347 @@ impl Context {
         &mut self.code
     }
 
+    // Global is defined where its label is, without a span it has a default location
     pub fn add_glob(&mut self, glob: Glob) -> Result<(), String> {
-        if self.unique_labels.contains(glob.get_name()) {
-            return Err(String::from(format!("Label with name '{}' already exist", glob.get_name())))
-        }
+        let location = glob.get_span().map(|span| span.location.clone()).unwrap_or_default();
+        self.define_symbol(glob.get_name(), SymbolKind::Glob, location)?;
         self.globs.push(glob);
         Ok(())
     }
 
+    // Registers a code label, the label instruction is pushed by the caller
+    pub fn define_label(&mut self, name: &str, location: Location) -> Result<(), String> {
+        self.define_symbol(name, SymbolKind::Label, location)
+    }
+
+    fn define_symbol(&mut self, name: &str, kind: SymbolKind, location: Location) -> Result<(), String> {
+        if let Some(symbol) = self.symbols.get(name) {
+            return Err(format!("{} '{}' is already defined at {}", symbol.kind, name, symbol.location));
+        }
+        self.symbols.insert(name.to_string(), Symbol { kind, location });
+        Ok(())
+    }
+
+    pub fn get_symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    // Adds string operand as a global and returns its generated name
+    pub fn add_string_literal(&mut self, value: Vec<u8>) -> String {
+        let name = format!(".string_{}", self.string_literal_count);
+        self.string_literal_count += 1;
+        self.globs.push(Glob::new_with_value(name.clone(), value));
+        name
+    }
+
+    pub fn define_constant(&mut self, name: &str, value: i64, location: Location) -> Result<(), String> {
+        self.define_symbol(name, SymbolKind::Constant, location)?;
+        self.constants.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    pub fn get_constant(&self, name: &str) -> Option<i64> {
+        self.constants.get(name).copied()
+    }
+
+    pub fn get_section(&self) -> Section {
+        self.section
+    }
+
+    pub fn set_section(&mut self, section: Section) {
+        self.section = section;
+    }
+
     pub fn get_globs(&self) -> &Vec<Glob> {
         &self.globs
     }
 
+    pub fn set_entry(&mut self, name: &str, span: Option<Span>) -> Result<(), String> {
+        if let Some((entry, entry_span)) = &self.entry {
+            return Err(match entry_span {
+                Some(entry_span) => format!("entry point is already set to '{}' at {}", entry, entry_span.location),
+                None => format!("entry point is already set to '{}'", entry),
+            });
+        }
+        self.entry = Some((name.to_string(), span));
+        Ok(())
+    }
+
+    pub fn get_entry(&self) -> Option<&str> {
+        self.entry.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    // Raw images have no header to record the entry point, a jump to it at 0x00 is used instead
+    pub fn add_entry_stub(&mut self) {
+        if let Some((name, span)) = &self.entry {
+            let mut instruction = Instruction::new_with_literal(Opcode::Jump, Literal::Label(name.clone()));
+            if let Some(span) = span {
+                instruction.set_span(span.clone());
+            }
+            self.code.get_mut_code().insert(0, instruction);
+        }
+    }
+
     pub fn write_binary(&self) -> Result<Vec<u8>, String> {
-        let mut bin = OutBin::new();
+        self.build_binary().map_err(|diagnostics| diagnostics[0].summary())
+    }
+
+    pub fn build_binary(&self) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        self.build_binary_with_symbols().map(|(bytes, _)| bytes)
+    }
 
-        self.code.encode(&mut bin)?;
+    // Encodes code followed by globals and returns the size of the code
+    fn encode(&self, bin: &mut OutBin, diagnostics: &mut Vec<Diagnostic>) -> u16 {
+        for instruction in self.code.get_code() {
+            if let Some(span) = instruction.get_span().filter(|_| !instruction.get_opcode().is_pseudo()) {
+                bin.lines.push((bin.code.len() as u16, span.location.clone()));
+            }
+            if let Err(err) = instruction.encode(bin) {
+                diagnostics.push(new_diagnostic(&err, instruction.get_span()));
+            }
+        }
+        let code_size = bin.code.len() as u16;
 
         for glob in &self.globs {
-            glob.encode(&mut bin)?;
+            if let Err(err) = glob.encode(bin) {
+                diagnostics.push(new_diagnostic(&err, glob.get_span()));
+            }
         }
+        code_size
+    }
 
-        for (offset, label_dest) in bin.addresses_to_update.iter() {
+    // Names and addresses of labels and globals, sorted by address
+    fn encoded_symbols<'a>(&self, bin: &'a OutBin) -> Vec<(u16, &'a str, SymbolKind)> {
+        let mut symbols: Vec<(u16, &str, SymbolKind)> = bin.address_table.iter().map(|(name, address)| {
+            // String literals are anonymous globals and aren't in the symbol table
+            let kind = self.get_symbol(name).map_or(SymbolKind::Glob, |symbol| symbol.kind);
+            (*address, name.as_str(), kind)
+        }).collect();
+        symbols.sort_by_key(|(address, name, _)| (*address, *name));
+        symbols
+    }
+
+    // Encodes the program and resolves all addresses. Reports every error instead of stopping at the first one.
+    pub fn build_binary_with_symbols(&self) -> Result<(Vec<u8>, SymbolMap), Vec<Diagnostic>> {
+        let mut bin = OutBin::new();
+        let mut diagnostics = vec![];
+        let code_size = self.encode(&mut bin, &mut diagnostics);
+
+        // Relocation errors are reported in the order they appear in the binary
+        let mut relocation_errors = vec![];
+        for (&offset, label_dest) in bin.addresses_to_update.iter() {
             match bin.address_table.get(label_dest) {
                 Some(dest_offset) => {
                     let bytes = dest_offset.to_le_bytes();
-                    let offset_as_idx = *offset as usize;
-                    bin.code[offset_as_idx + 0] = bytes[0];
+                    let offset_as_idx = offset as usize;
+                    bin.code[offset_as_idx] = bytes[0];
                     bin.code[offset_as_idx + 1] = bytes[1];
                 }
+                None => relocation_errors.push((offset, format!("Unknown label '{0}'", label_dest))),
+            }
+        }
+
+        let address_table = &bin.address_table;
+        let resolve = |name: &str| self.get_constant(name).or_else(|| address_table.get(name).map(|address| *address as i64));
+        for (&offset, (expression, width)) in bin.expressions_to_update.iter() {
+            let offset_as_idx = offset as usize;
+            let result = expression.evaluate(&resolve).and_then(|value| match width {
+                1 => lexer::label_value_to_u8(value).map(|value| vec![value]),
+                _ => lexer::label_value_to_u16(value).map(|value| value.to_le_bytes().to_vec()),
+            });
+            match result {
+                Ok(bytes) => bin.code[offset_as_idx..offset_as_idx + bytes.len()].copy_from_slice(&bytes),
+                Err(err) => relocation_errors.push((offset, err)),
+            }
+        }
+
+        relocation_errors.sort();
+        for (offset, err) in relocation_errors {
+            diagnostics.push(new_diagnostic(&err, bin.spans.get(&offset)));
+        }
+
+        let entry = match &self.entry {
+            Some((name, span)) => match bin.address_table.get(name) {
+                Some(address) if *address < code_size => Some(*address),
+                Some(_) => {
+                    diagnostics.push(new_diagnostic(&format!("entry point '{}' is not in the code", name), span.as_ref()));
+                    None
+                }
                 None => {
-                    return Err(String::from(format!("Unknown label '{0}'", label_dest)));
+                    diagnostics.push(new_diagnostic(&format!("Unknown entry point '{}'", name), span.as_ref()));
+                    None
                 }
+            },
+            None => None,
+        };
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+
+        let mut symbols = SymbolMap::new(code_size);
+        symbols.entry = entry;
+        for (address, location) in bin.lines.iter() {
+            symbols.add_line(*address, &location.file, location.line);
+        }
+        for (address, name, kind) in self.encoded_symbols(&bin) {
+            symbols.add_symbol(name, address, kind);
+        }
+        Ok((bin.get_bytes(), symbols))
+    }
+
+    // Encodes the program without resolving addresses, every reference to a label or global
+    // becomes a relocation patched by the linker. Constants are substituted.
+    pub fn build_object(&self) -> Result<ObjectFile, Vec<Diagnostic>> {
+        let mut bin = OutBin::new();
+        let mut diagnostics = vec![];
+        let code_size = self.encode(&mut bin, &mut diagnostics);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+
+        let split = |address: u16| match address < code_size {
+            true => (Section::Code, address),
+            false => (Section::Data, address - code_size),
+        };
+
+        let mut object = ObjectFile::new();
+        object.entry = self.get_entry().map(|name| name.to_string());
+        object.code = bin.code[..code_size as usize].to_vec();
+        object.data = bin.code[code_size as usize..].to_vec();
+        for (offset, location) in bin.lines.iter() {
+            object.lines.push(LineMapping { address: *offset, file: location.file.to_string(), line: location.line });
+        }
+
+        for (address, name, kind) in self.encoded_symbols(&bin) {
+            let (section, offset) = split(address);
+            object.symbols.push(ObjectSymbol { name: name.to_string(), kind, section, offset });
+        }
+
+        let mut relocations: Vec<(u16, Expression, u8)> = bin.addresses_to_update.iter()
+            .map(|(address, label)| (*address, Expression::Symbol(label.clone()), 2))
+            .collect();
+        for (address, (expression, width)) in bin.expressions_to_update.iter() {
+            let expression = expression.substitute(&|name| self.get_constant(name));
+            relocations.push((*address, expression, *width as u8));
+        }
+        relocations.sort_by_key(|(address, _, _)| *address);
+        for (address, expression, width) in relocations {
+            let (section, offset) = split(address);
+            object.relocations.push(Relocation { section, offset, width, expression });
+        }
+
+        Ok(object)
+    }
+
+    // Decodes an image back into instructions and globals, building the context gives the same image.
+    // Symbol map names the labels and tells where global data starts, without it the whole image is
+    // decoded as code and branch targets get generated labels.
+    pub fn load_binary(&mut self, bytes: &[u8], symbols: Option<&SymbolMap>) -> Result<(), String> {
+        if !self.code.get_code().is_empty() || !self.globs.is_empty() {
+            return Err(String::from("binary can only be loaded into an empty context"));
+        }
+        if bytes.len() > u16::MAX as usize + 1 {
+            return Err(format!("image of {} bytes doesn't fit into memory", bytes.len()));
+        }
+        let code_size = symbols.map_or(bytes.len(), |symbols| symbols.code_size as usize);
+        if code_size > bytes.len() {
+            return Err(format!("code size 0x{:04x} is larger than the image", code_size));
+        }
+
+        let mut instructions = vec![];
+        let mut address = 0;
+        while address < code_size {
+            match disassembler::decode_instruction(&bytes[..code_size], address as u16) {
+                Some(instruction) => {
+                    address += instruction.size();
+                    instructions.push(instruction);
+                }
+                None => return Err(format!("invalid instruction at 0x{:04x}", address)),
+            }
+        }
+
+        let mut names = AddressNames::new(symbols, bytes.len());
+        for instruction in instructions.iter().filter(|instruction| instruction.opcode.is_branch()) {
+            if let Some(target) = instruction.operand.filter(|target| (*target as usize) < code_size) {
+                names.add_label(target);
             }
         }
 
-        Ok(bin.get_bytes())
+        let entry = symbols.and_then(|symbols| symbols.entry);
+        if let Some(entry) = entry.filter(|entry| (*entry as usize) < code_size) {
+            names.add_label(entry);
+        }
+
+        let boundaries: HashSet<u16> = instructions.iter().map(|instruction| instruction.address).collect();
+        let mut code_names = names.iter().take_while(|(address, _)| (*address as usize) < code_size);
+        if let Some((address, names)) = code_names.find(|(address, _)| !boundaries.contains(address)) {
+            return Err(format!("'{}' at 0x{:04x} points inside an instruction", names[0].name, address));
+        }
+
+        for instruction in instructions.iter() {
+            for symbol in names.names_at(instruction.address) {
+                self.push_label(&symbol.name)?;
+            }
+            let literal = match (instruction.opcode.operand_kind(), instruction.operand) {
+                (OperandKind::U16, Some(address)) => match names.name_at(address) {
+                    Some(name) => Literal::Label(name.to_string()),
+                    None => Literal::Address(address),
+                },
+                (_, Some(value)) => Literal::Const(value as u8),
+                _ => Literal::None(),
+            };
+            let mut decoded = Instruction::new_with_literal(instruction.opcode, literal);
+            // Keeps source lines of the symbol map when the context is built again
+            let mapping = symbols.and_then(|symbols| symbols.line_at(instruction.address)).filter(|mapping| mapping.address == instruction.address);
+            if let Some(mapping) = mapping {
+                decoded.set_span(Span::new(Location::new(&mapping.file, mapping.line), 1, 0));
+            }
+            self.code.push_instruction(decoded);
+        }
+
+        // Labels right after the code, globals there take the data
+        let mut data_names: Vec<(usize, &String)> = vec![];
+        for (address, symbols) in names.iter().filter(|(address, _)| *address as usize >= code_size) {
+            for symbol in symbols.iter() {
+                match address as usize == code_size && symbol.kind == SymbolKind::Label {
+                    true => self.push_label(&symbol.name)?,
+                    false => data_names.push((address as usize, &symbol.name)),
+                }
+            }
+        }
+
+        let unnamed_data = format!("data_{:04x}", code_size);
+        if code_size < bytes.len() && data_names.first().is_none_or(|(address, _)| *address != code_size) {
+            data_names.insert(0, (code_size, &unnamed_data));
+        }
+        for (index, (address, name)) in data_names.iter().enumerate() {
+            // Glob takes bytes until the next one, globals at the same address are empty except the last one
+            let end = data_names.get(index + 1).map_or(bytes.len(), |(next_address, _)| *next_address);
+            self.add_glob(Glob::new_with_value(name.to_string(), bytes[*address..end].to_vec()))?;
+        }
+
+        if let Some(entry) = entry {
+            match names.names_at(entry).first() {
+                Some(symbol) if (entry as usize) < code_size => self.set_entry(&symbol.name, None)?,
+                _ => return Err(format!("entry point 0x{:04x} is outside of the code", entry)),
+            }
+        }
+
+        Ok(())
     }
 
-    // // TODO finish implementing binary loading
-    // pub fn load_binary(&mut self, file_name: &str) -> Result<(), String> {
-    //     Ok(())
-    // }
-}
\ No newline at end of file
+    fn push_label(&mut self, name: &str) -> Result<(), String> {
+        self.define_label(name, Location::default())?;
+        let instruction = Instruction::new_with_literal(Opcode::Label, Literal::Label(name.to_string()));
+        self.code.push_instruction(instruction);
+        Ok(())
+    }
+}
+
+fn new_diagnostic(message: &str, span: Option<&Span>) -> Diagnostic {
+    match span {
+        Some(span) => Diagnostic::new_with_span(message, span.clone()),
+        None => Diagnostic::new(message),
+    }
+}
@@ -0,0 +1,180 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::fmt;
+use std::rc::Rc;
+
+// Line in a source file. File is empty when the source didn't come from a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    // Shared by all lines of the file
+    pub file: Rc<str>,
+    pub line: usize,
+    // Tells apart lines produced by different expansions of the same macro line, 0 outside of macros
+    pub expansion: usize,
+}
+
+impl Location {
+    pub fn new(file: &str, line: usize) -> Location {
+        Location { file: Rc::from(file), line, expansion: 0 }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file.is_empty() {
+            true => write!(f, "line {}", self.line),
+            false => write!(f, "{}:{}", self.file, self.line),
+        }
+    }
+}
+
+// Part of a source line. Column is 1-based and counted in characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Span {
+    pub location: Location,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn new(location: Location, column: usize, length: usize) -> Span {
+        Span { location, column, length }
+    }
+}
+
+// Line of the program with the place it came from. Notes are added to every diagnostic
+// about the line, e.g. to tell which macro invocation produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Location,
+    pub notes: Vec<String>,
+}
+
+impl SourceLine {
+    pub fn new(text: &str, file: &str, line: usize) -> SourceLine {
+        SourceLine { text: text.to_string(), location: Location::new(file, line), notes: vec![] }
+    }
+
+    // Numbers lines of a file starting from 1
+    pub fn from_lines(lines: &[String], file: &str) -> Vec<SourceLine> {
+        let file: Rc<str> = Rc::from(file);
+        lines.iter().enumerate().map(|(index, line)| SourceLine {
+            text: line.clone(),
+            location: Location { file: file.clone(), line: index + 1, expansion: 0 },
+            notes: vec![],
+        }).collect()
+    }
+
+    pub fn span(&self, column: usize, length: usize) -> Span {
+        Span::new(self.location.clone(), column, length)
+    }
+
+    // Span of the whole line without surrounding whitespace
+    pub fn full_span(&self) -> Span {
+        let trimmed = self.text.trim_start();
+        let column = self.text.chars().count() - trimmed.chars().count() + 1;
+        self.span(column, trimmed.trim_end().chars().count())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    // Text of the line the span points at
+    pub source: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: &str) -> Diagnostic {
+        Diagnostic { message: message.to_string(), span: None, source: None, notes: vec![] }
+    }
+
+    pub fn new_with_span(message: &str, span: Span) -> Diagnostic {
+        Diagnostic { message: message.to_string(), span: Some(span), source: None, notes: vec![] }
+    }
+
+    // Diagnostic pointing at part of the line, it gets the line's text and notes
+    pub fn at(message: &str, line: &SourceLine, column: usize, length: usize) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new_with_span(message, line.span(column, length));
+        diagnostic.attach_source(line);
+        diagnostic
+    }
+
+    pub fn attach_source(&mut self, line: &SourceLine) {
+        self.source = Some(line.text.clone());
+        self.notes.extend(line.notes.iter().cloned());
+    }
+
+    pub fn add_note(&mut self, note: &str) {
+        self.notes.push(note.to_string());
+    }
+
+    // One line summary, e.g. "main.srd:3:5: Unknown label 'foo'".
+    // Without a file it's just "3: Unknown label 'foo'".
+    pub fn summary(&self) -> String {
+        match &self.span {
+            Some(span) if span.location.file.is_empty() => format!("{}: {}", span.location.line, self.message),
+            Some(span) => format!("{}:{}:{}: {}", span.location.file, span.location.line, span.column, self.message),
+            None => self.message.clone(),
+        }
+    }
+}
+
+// error: Unknown label 'foo'
+//   --> main.srd:3:10
+//    |
+//  3 |     jump foo
+//    |          ^^^
+//    = note: ...
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {}", self.message)?;
+
+        let gutter = match &self.span {
+            Some(span) => {
+                let gutter = " ".repeat(span.location.line.to_string().len());
+                match span.location.file.is_empty() {
+                    true => write!(f, "\n{}--> line {}:{}", gutter, span.location.line, span.column)?,
+                    false => write!(f, "\n{}--> {}:{}:{}", gutter, span.location.file, span.location.line, span.column)?,
+                }
+
+                if let Some(source) = &self.source {
+                    // Keep tabs so the caret lines up with the source
+                    let padding: String = source.chars().take(span.column.saturating_sub(1))
+                        .map(|c| if c == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    write!(f, "\n{} |", gutter)?;
+                    write!(f, "\n{} | {}", span.location.line, source.trim_end())?;
+                    write!(f, "\n{} | {}{}", gutter, padding, "^".repeat(span.length.max(1)))?;
+                }
+                gutter
+            }
+            None => String::new(),
+        };
+
+        for note in self.notes.iter() {
+            write!(f, "\n{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryFrom;
+
+use shard_core::opcodes::{Opcode, OperandKind};
+
+use crate::symbols::{AddressNames, SymbolMap};
+
+// Bytes of global data shown on one line
+const DATA_LINE_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    pub opcode: Opcode,
+    // Value that follows the opcode, addresses are little-endian
+    pub operand: Option<u16>,
+}
+
+impl DecodedInstruction {
+    // Size in bytes with the operand
+    pub fn size(&self) -> usize {
+        1 + self.opcode.operand_kind().size()
+    }
+}
+
+// None when the byte is not an opcode or the operand doesn't fit into the image
+pub fn decode_instruction(image: &[u8], address: u16) -> Option<DecodedInstruction> {
+    let start = address as usize;
+    let opcode = Opcode::try_from(*image.get(start)?).ok().filter(|opcode| !opcode.is_pseudo())?;
+    let operand = match opcode.operand_kind() {
+        OperandKind::None => None,
+        OperandKind::U8 => Some(*image.get(start + 1)? as u16),
+        OperandKind::U16 => Some(u16::from_le_bytes([*image.get(start + 1)?, *image.get(start + 2)?])),
+    };
+    Some(DecodedInstruction { address, opcode, operand })
+}
+
+// Instruction as it would be written in assembly, addresses of known symbols are shown by name
+pub fn format_instruction(instruction: &DecodedInstruction, names: &AddressNames) -> String {
+    let mnemonic = instruction.opcode.to_string();
+    match (instruction.opcode.operand_kind(), instruction.operand) {
+        (OperandKind::U16, Some(address)) => match names.name_at(address) {
+            Some(name) => format!("{} {}", mnemonic, name),
+            None => format!("{} 0x{:04x}", mnemonic, address),
+        },
+        (_, Some(value)) => format!("{} 0x{:02x}", mnemonic, value),
+        _ => mnemonic.to_string(),
+    }
+}
+
+// Lists the image with addresses, raw bytes and mnemonics. With a symbol map labels get their names,
+// instructions get their source lines and bytes after the code are shown as global data, without it
+// the whole image is treated as code.
+// Branch targets without a name get a generated label. Images that don't fit into the 16-bit
+// address space are rejected.
+pub fn disassemble(image: &[u8], symbols: Option<&SymbolMap>) -> Result<String, String> {
+    if image.len() > u16::MAX as usize + 1 {
+        return Err(format!("Image of {} bytes doesn't fit into {} bytes of address space", image.len(), u16::MAX as usize + 1));
+    }
+
+    let code_size = symbols.map_or(image.len(), |symbols| image.len().min(symbols.code_size as usize));
+
+    // Bytes that aren't instructions are kept as None
+    let mut instructions = vec![];
+    let mut address = 0;
+    while address < code_size {
+        let instruction = decode_instruction(&image[..code_size], address as u16);
+        let size = instruction.as_ref().map_or(1, |instruction| instruction.size());
+        instructions.push((address, instruction));
+        address += size;
+    }
+
+    let mut names = AddressNames::new(symbols, image.len());
+    let branch_targets = instructions.iter()
+        .filter_map(|(_, instruction)| instruction.as_ref())
+        .filter(|instruction| instruction.opcode.is_branch())
+        .filter_map(|instruction| instruction.operand)
+        .filter(|target| (*target as usize) < code_size);
+    for target in branch_targets {
+        names.add_label(target);
+    }
+
+    let mut output = String::new();
+    let write_labels = |output: &mut String, address: usize| {
+        for symbol in names.names_at(address as u16) {
+            output.push_str(&format!("{}:\n", symbol.name));
+        }
+    };
+
+    for (address, instruction) in instructions {
+        write_labels(&mut output, address);
+        let (size, text) = match instruction {
+            Some(instruction) => {
+                let text = format_instruction(&instruction, &names);
+                // Source line from the symbol map
+                match symbols.and_then(|symbols| symbols.line_at(address as u16)).filter(|mapping| mapping.address as usize == address) {
+                    Some(mapping) => (instruction.size(), format!("{:<23} ; {}", text, mapping)),
+                    None => (instruction.size(), text),
+                }
+            }
+            None => (1, format!("; unknown opcode 0x{:02x}", image[address])),
+        };
+        output.push_str(&format_line(address, &image[address..address + size], 8, &text));
+    }
+
+    let mut address = code_size;
+    while address < image.len() {
+        write_labels(&mut output, address);
+        // Data line ends before the next symbol
+        let next_symbol = names.iter().find(|(next_address, _)| *next_address as usize > address)
+            .map_or(image.len(), |(next_address, _)| next_address as usize);
+        let end = image.len().min(next_symbol).min(address + DATA_LINE_SIZE);
+        let bytes = &image[address..end];
+        let text: String = bytes.iter().map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }).collect();
+        output.push_str(&format_line(address, bytes, DATA_LINE_SIZE * 3 - 1, &format!("; \"{}\"", text)));
+        address = end;
+    }
+    // Labels that mark the end of the image
+    if image.len() <= u16::MAX as usize {
+        write_labels(&mut output, image.len());
+    }
+
+    Ok(output)
+}
+
+//     0002  05 12 00  push_addr hello_world
+fn format_line(address: usize, bytes: &[u8], width: usize, text: &str) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("    {:04x}  {:<width$}  {}\n", address, bytes.join(" "), text, width = width)
+}
@@ -0,0 +1,339 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::lexer;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    // 1 when the value is 0, otherwise 0
+    Not,
+    // Low byte of the value
+    Lo,
+    // High byte of 16-bit value
+    Hi,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    Shr,
+    And,
+    Or,
+    // Comparisons and logical operators evaluate to 1 or 0
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+// Constant expression of an operand. Symbols are constants or labels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+    Value(i64),
+    Symbol(String),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Token {
+    Value(i64),
+    Symbol(String),
+    Op(String),
+    Open,
+    Close,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let expression = parser.parse_binary(0)?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(expression),
+            Some(_) => Err(format!("unexpected token in expression '{}'", source)),
+        }
+    }
+
+    // Evaluates expression using resolve for symbol values
+    pub fn evaluate(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        match self {
+            Expression::Value(value) => Ok(*value),
+            Expression::Symbol(name) => match resolve(name) {
+                Some(value) => Ok(value),
+                None => Err(format!("Unknown label '{}'", name)),
+            },
+            Expression::Unary(op, expression) => {
+                let value = expression.evaluate(resolve)?;
+                match op {
+                    UnaryOp::Neg => value.checked_neg().ok_or_else(|| String::from("expression overflow")),
+                    UnaryOp::Not => Ok((value == 0) as i64),
+                    UnaryOp::Lo => Ok(value & 0xff),
+                    UnaryOp::Hi => Ok((value >> 8) & 0xff),
+                }
+            }
+            Expression::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(resolve)?;
+                let rhs = rhs.evaluate(resolve)?;
+                let result = match op {
+                    BinaryOp::Add => lhs.checked_add(rhs),
+                    BinaryOp::Sub => lhs.checked_sub(rhs),
+                    BinaryOp::Mul => lhs.checked_mul(rhs),
+                    BinaryOp::Div if rhs == 0 => return Err(String::from("division by zero in expression")),
+                    BinaryOp::Div => lhs.checked_div(rhs),
+                    BinaryOp::Shl => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+                    BinaryOp::Shr => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+                    BinaryOp::And => Some(lhs & rhs),
+                    BinaryOp::Or => Some(lhs | rhs),
+                    BinaryOp::Eq => Some((lhs == rhs) as i64),
+                    BinaryOp::Ne => Some((lhs != rhs) as i64),
+                    BinaryOp::Lt => Some((lhs < rhs) as i64),
+                    BinaryOp::Gt => Some((lhs > rhs) as i64),
+                    BinaryOp::Le => Some((lhs <= rhs) as i64),
+                    BinaryOp::Ge => Some((lhs >= rhs) as i64),
+                    BinaryOp::LogicalAnd => Some((lhs != 0 && rhs != 0) as i64),
+                    BinaryOp::LogicalOr => Some((lhs != 0 || rhs != 0) as i64),
+                };
+                result.ok_or_else(|| String::from("expression overflow"))
+            }
+        }
+    }
+
+    // Replaces symbols that resolve to a value, e.g. constants, the rest are kept
+    pub fn substitute(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Expression {
+        match self {
+            Expression::Value(value) => Expression::Value(*value),
+            Expression::Symbol(name) => match resolve(name) {
+                Some(value) => Expression::Value(value),
+                None => Expression::Symbol(name.clone()),
+            },
+            Expression::Unary(op, expression) => Expression::Unary(*op, Box::new(expression.substitute(resolve))),
+            Expression::Binary(op, lhs, rhs) => {
+                Expression::Binary(*op, Box::new(lhs.substitute(resolve)), Box::new(rhs.substitute(resolve)))
+            }
+        }
+    }
+
+    // Names of all symbols the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Value(_) => vec![],
+            Expression::Symbol(name) => vec![name.as_str()],
+            Expression::Unary(_, expression) => expression.symbols(),
+            Expression::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+}
+
+// Written so that it parses back into the same value, binary operations are always in parentheses
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Value(value) if *value < 0 => write!(f, "(0 - {})", value.unsigned_abs()),
+            Expression::Value(value) => write!(f, "{}", value),
+            Expression::Symbol(name) => write!(f, "{}", name),
+            Expression::Unary(UnaryOp::Neg, expression) => write!(f, "-{}", expression),
+            Expression::Unary(UnaryOp::Not, expression) => write!(f, "!{}", expression),
+            Expression::Unary(UnaryOp::Lo, expression) => write!(f, "lo({})", expression),
+            Expression::Unary(UnaryOp::Hi, expression) => write!(f, "hi({})", expression),
+            Expression::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::Shl => "<<",
+                    BinaryOp::Shr => ">>",
+                    BinaryOp::And => "&",
+                    BinaryOp::Or => "|",
+                    BinaryOp::Eq => "==",
+                    BinaryOp::Ne => "!=",
+                    BinaryOp::Lt => "<",
+                    BinaryOp::Gt => ">",
+                    BinaryOp::Le => "<=",
+                    BinaryOp::Ge => ">=",
+                    BinaryOp::LogicalAnd => "&&",
+                    BinaryOp::LogicalOr => "||",
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut position = 0;
+
+    while position < chars.len() {
+        let c = chars[position];
+        if c.is_whitespace() {
+            position += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            position += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            position += 1;
+        } else if "<>=!&|".contains(c) {
+            // Two character operators take precedence over < > ! & |
+            let pair: String = chars[position..chars.len().min(position + 2)].iter().collect();
+            if ["<<", ">>", "<=", ">=", "==", "!=", "&&", "||"].contains(&pair.as_str()) {
+                tokens.push(Token::Op(pair));
+                position += 2;
+            } else if c == '=' {
+                return Err(format!("unknown operator '{}' in expression", c));
+            } else {
+                tokens.push(Token::Op(c.to_string()));
+                position += 1;
+            }
+        } else if "+-*/".contains(c) {
+            tokens.push(Token::Op(c.to_string()));
+            position += 1;
+        } else if c == '\'' {
+            // Character literal, quote can be escaped
+            let start = position;
+            position += 1;
+            while position < chars.len() && chars[position] != '\'' {
+                position += if chars[position] == '\\' { 2 } else { 1 };
+            }
+            position += 1;
+            let literal: String = chars[start..position.min(chars.len())].iter().collect();
+            tokens.push(Token::Value(lexer::parse_value(&literal)?));
+        } else if is_symbol_char(c) {
+            let start = position;
+            while position < chars.len() && is_symbol_char(chars[position]) {
+                position += 1;
+            }
+            let word: String = chars[start..position].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Token::Value(lexer::parse_value(&word)?));
+            } else {
+                tokens.push(Token::Symbol(word));
+            }
+        } else {
+            return Err(format!("unexpected character '{}' in expression", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+// Lowest to highest binding, same as in C
+const PRECEDENCE: [&[&str]; 9] = [
+    &["||"], &["&&"], &["|"], &["&"], &["==", "!="], &["<", ">", "<=", ">="], &["<<", ">>"], &["+", "-"], &["*", "/"],
+];
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expression, String> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.position) {
+            if !PRECEDENCE[level].contains(&op.as_str()) {
+                break;
+            }
+            let op = match op.as_str() {
+                "||" => BinaryOp::LogicalOr,
+                "&&" => BinaryOp::LogicalAnd,
+                "==" => BinaryOp::Eq,
+                "!=" => BinaryOp::Ne,
+                "<" => BinaryOp::Lt,
+                ">" => BinaryOp::Gt,
+                "<=" => BinaryOp::Le,
+                ">=" => BinaryOp::Ge,
+                "|" => BinaryOp::Or,
+                "&" => BinaryOp::And,
+                "<<" => BinaryOp::Shl,
+                ">>" => BinaryOp::Shr,
+                "+" => BinaryOp::Add,
+                "-" => BinaryOp::Sub,
+                "*" => BinaryOp::Mul,
+                _ => BinaryOp::Div,
+            };
+            self.position += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Op(op)) if op == "-" => Ok(Expression::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?))),
+            Some(Token::Op(op)) if op == "!" => Ok(Expression::Unary(UnaryOp::Not, Box::new(self.parse_unary()?))),
+            Some(Token::Value(value)) => Ok(Expression::Value(value)),
+            Some(Token::Symbol(name)) => {
+                let op = match name.as_str() {
+                    "lo" => UnaryOp::Lo,
+                    "hi" => UnaryOp::Hi,
+                    _ => return Ok(Expression::Symbol(name)),
+                };
+                if self.tokens.get(self.position) != Some(&Token::Open) {
+                    return Ok(Expression::Symbol(name));
+                }
+                Ok(Expression::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Open) => {
+                let expression = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err(String::from("missing ')' in expression")),
+                }
+            }
+            Some(Token::Op(op)) => Err(format!("unexpected '{}' in expression", op)),
+            Some(Token::Close) => Err(String::from("unexpected ')' in expression")),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
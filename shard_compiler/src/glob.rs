@@ -17,20 +17,31 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+use crate::diagnostic::Span;
+use crate::expression::Expression;
 use crate::out_bin::OutBin;
 
 pub struct Glob {
     name: String,
     value: Vec<u8>,
+    // Bytes at given offsets of the value that are resolved from expressions
+    expressions: Vec<(usize, Expression)>,
+    // Label in the source, used for diagnostics
+    span: Option<Span>,
+    expression_spans: Vec<Option<Span>>,
 }
 
 impl Glob {
     pub fn new(name: String) -> Glob {
-        Glob { name, value: vec![] }
+        Glob { name, value: vec![], expressions: vec![], span: None, expression_spans: vec![] }
     }
 
     pub fn new_with_value(name: String, value: Vec<u8>) -> Glob {
-        Glob { name, value }
+        Glob { name, value, expressions: vec![], span: None, expression_spans: vec![] }
+    }
+
+    pub fn new_with_expressions(name: String, value: Vec<u8>, expressions: Vec<(usize, Expression)>) -> Glob {
+        Glob { name, value, expressions, span: None, expression_spans: vec![] }
     }
 
     pub fn get_name(&self) -> &String {
@@ -41,12 +52,34 @@ impl Glob {
         &self.value
     }
 
+    pub fn get_expressions(&self) -> &Vec<(usize, Expression)> {
+        &self.expressions
+    }
+
+    pub fn get_span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
+
+    pub fn set_span(&mut self, span: Span) {
+        self.span = Some(span);
+    }
+
+    // Spans of the expressions, in the same order
+    pub fn set_expression_spans(&mut self, spans: Vec<Option<Span>>) {
+        self.expression_spans = spans;
+    }
+
     pub fn encode(&self, bin: &mut OutBin) -> Result<(), String> {
         let glob_address = bin.code.len() as u16;
         bin.code.extend_from_slice(&self.value);
+        for (index, (offset, expression)) in self.expressions.iter().enumerate() {
+            let address = glob_address + *offset as u16;
+            bin.add_span(address, self.expression_spans.get(index).and_then(|span| span.as_ref()));
+            bin.expressions_to_update.insert(address, (expression.clone(), 1));
+        }
         match bin.address_table.insert(self.name.clone(), glob_address) {
             None => Ok(()),
-            Some(_) => Err(String::from(format!("'{}' label already exist", self.name)))
+            Some(_) => Err(format!("'{}' label already exist", self.name))
         }
     }
 }
\ No newline at end of file
@@ -19,6 +19,8 @@
 
 use shard_core::opcodes::Opcode;
 
+use crate::diagnostic::Span;
+use crate::expression::Expression;
 use crate::out_bin::OutBin;
 
 #[derive(Debug, PartialEq)]
@@ -27,20 +29,24 @@ pub enum Literal {
     Const(u8),
     Address(u16),
     Label(String),
+    // Resolved once all label addresses are known
+    Expression(Expression),
 }
 
 pub struct Instruction {
     opcode: Opcode,
     literal: Literal,
+    // Operand or the label in the source, used for diagnostics
+    span: Option<Span>,
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode, literal: Literal::None() }
+        Instruction { opcode, literal: Literal::None(), span: None }
     }
 
     pub fn new_with_literal(opcode: Opcode, literal: Literal) -> Instruction {
-        Instruction { opcode, literal }
+        Instruction { opcode, literal, span: None }
     }
 
     pub fn get_opcode(&self) -> Opcode {
@@ -55,22 +61,27 @@ impl Instruction {
         &mut self.literal
     }
 
+    pub fn get_span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
+
+    pub fn set_span(&mut self, span: Span) {
+        self.span = Some(span);
+    }
+
     pub fn encode(&self, bin: &mut OutBin) -> Result<(), String> {
-        match &self.opcode {
-            Opcode::Label => {
-                match &self.literal {
-                    Literal::Label(label) => {
-                        // Pseudo instruction - nothing to encode since this is jump destination.
-                        // Putting destination into the map.
-                        match bin.address_table.insert(label.clone(), bin.code.len() as u16) {
-                            None => return Ok(()),
-                            Some(_) => return Err(String::from(format!("'{}' label already exist", label)))
-                        };
-                    }
-                    _ => return Err(String::from(format!("Invalid literal type with Label")))
+        if self.opcode == Opcode::Label {
+            match &self.literal {
+                Literal::Label(label) => {
+                    // Pseudo instruction - nothing to encode since this is jump destination.
+                    // Putting destination into the map.
+                    match bin.address_table.insert(label.clone(), bin.code.len() as u16) {
+                        None => return Ok(()),
+                        Some(_) => return Err(format!("'{}' label already exist", label))
+                    };
                 }
+                _ => return Err("Invalid literal type with Label".to_string())
             }
-            _ => {}
         }
 
         bin.code.push(self.opcode as u8);
@@ -88,15 +99,24 @@ impl Instruction {
                 bin.code.push(*value);
             }
             Literal::Address(value) => {
+                // Addresses are little-endian, same as in the VM memory and stacks
                 let bytes = value.to_le_bytes();
                 bin.code.extend_from_slice(&bytes);
             }
             Literal::Label(value) => {
+                bin.add_span(bin.code.len() as u16, self.span.as_ref());
                 bin.addresses_to_update.insert(bin.code.len() as u16, value.clone());
                 // Push temporary address
                 bin.code.push(0x00);
                 bin.code.push(0x00);
             }
+            Literal::Expression(expression) => {
+                let width = self.opcode.operand_kind().size();
+                bin.add_span(bin.code.len() as u16, self.span.as_ref());
+                bin.expressions_to_update.insert(bin.code.len() as u16, (expression.clone(), width));
+                // Push temporary value
+                bin.code.resize(bin.code.len() + width, 0x00);
+            }
         }
 
         Ok(())
@@ -0,0 +1,224 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryFrom;
+
+// Splits line into whitespace separated tokens and drops the comment.
+// Whitespace and ';' inside of quotes or parentheses are part of the token.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    Ok(tokenize_with_columns(line)?.into_iter().map(|(_, token)| token).collect())
+}
+
+// Same as tokenize, but also returns 1-based column of every token
+pub fn tokenize_with_columns(line: &str) -> Result<Vec<(usize, String)>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut token_column = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0;
+
+    for (index, c) in line.chars().enumerate() {
+        match quote {
+            Some(quote_char) => {
+                token.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote_char {
+                    quote = None;
+                }
+            }
+            None => {
+                if c == ';' {
+                    break;
+                } else if c.is_whitespace() && depth == 0 {
+                    if !token.is_empty() {
+                        tokens.push((token_column, std::mem::take(&mut token)));
+                    }
+                } else {
+                    match c {
+                        '"' | '\'' => quote = Some(c),
+                        '(' => depth += 1,
+                        ')' if depth > 0 => depth -= 1,
+                        _ => {}
+                    }
+                    if token.is_empty() {
+                        token_column = index + 1;
+                    }
+                    token.push(c);
+                }
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(format!("unterminated quote in '{}'", token));
+    }
+    if depth > 0 {
+        return Err(format!("unclosed parenthesis in '{}'", token));
+    }
+    if !token.is_empty() {
+        tokens.push((token_column, token));
+    }
+    Ok(tokens)
+}
+
+// Whether token is a value rather than a label name
+pub fn is_literal(token: &str) -> bool {
+    match token.chars().next() {
+        Some(c) => c.is_ascii_digit() || c == '-' || c == '\'' || c == '"' || token.starts_with("z\""),
+        None => false,
+    }
+}
+
+// Accepts 0x hex, 0b binary, decimal, negative decimal (as two's complement) and 'c' characters
+pub fn parse_u8(token: &str) -> Result<u8, String> {
+    value_to_u8(parse_value(token)?)
+}
+
+pub fn parse_u16(token: &str) -> Result<u16, String> {
+    value_to_u16(parse_value(token)?)
+}
+
+pub fn value_to_u8(value: i64) -> Result<u8, String> {
+    match value {
+        -128..=-1 => Ok(value as i8 as u8),
+        _ => u8::try_from(value).map_err(|_| format!("{} doesn't fit into a byte", value)),
+    }
+}
+
+pub fn value_to_u16(value: i64) -> Result<u16, String> {
+    match value {
+        -32768..=-1 => Ok(value as i16 as u16),
+        _ => u16::try_from(value).map_err(|_| format!("{} doesn't fit into 16 bits", value)),
+    }
+}
+
+// Expressions with labels are sizes and addresses, a negative result means the labels are out of order
+pub fn label_value_to_u8(value: i64) -> Result<u8, String> {
+    match value {
+        i64::MIN..=-1 => Err(format!("label arithmetic result {} is negative", value)),
+        _ => value_to_u8(value),
+    }
+}
+
+pub fn label_value_to_u16(value: i64) -> Result<u16, String> {
+    match value {
+        i64::MIN..=-1 => Err(format!("label arithmetic result {} is negative", value)),
+        _ => value_to_u16(value),
+    }
+}
+
+// String literals expand into their bytes, z"..." strings also get a NUL terminator.
+// Anything else is a single byte.
+pub fn parse_data(token: &str) -> Result<Vec<u8>, String> {
+    if let Some(string) = token.strip_prefix("z\"") {
+        let mut bytes = parse_string(&token[1..], string)?;
+        bytes.push(0x00);
+        Ok(bytes)
+    } else if let Some(string) = token.strip_prefix('"') {
+        parse_string(token, string)
+    } else {
+        Ok(vec![parse_u8(token)?])
+    }
+}
+
+pub fn parse_value(token: &str) -> Result<i64, String> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        parse_radix(hex, 16)?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        parse_radix(binary, 2)?
+    } else if let Some(character) = digits.strip_prefix('\'') {
+        if negative {
+            return Err(String::from("character can't be negative"));
+        }
+        let bytes = match character.strip_suffix('\'') {
+            Some(character) => unescape(character)?,
+            None => return Err(String::from("unterminated character literal")),
+        };
+        if bytes.len() != 1 {
+            return Err(String::from("character literal must be a single byte"));
+        }
+        bytes[0] as i64
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse::<i64>().map_err(|err| err.to_string())?
+    } else {
+        return Err(String::from("not a number"));
+    };
+
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_radix(digits: &str, radix: u32) -> Result<i64, String> {
+    // from_str_radix would also accept a sign after the prefix
+    if digits.starts_with('+') || digits.starts_with('-') {
+        return Err(String::from("invalid digit found in string"));
+    }
+    i64::from_str_radix(digits, radix).map_err(|err| err.to_string())
+}
+
+fn parse_string(token: &str, string: &str) -> Result<Vec<u8>, String> {
+    match string.strip_suffix('"') {
+        Some(string) => unescape(string),
+        None => Err(format!("unterminated string {}", token)),
+    }
+}
+
+// Supported escapes: \n \r \t \0 \\ \' \" and \xHH
+fn unescape(string: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut char_it = string.chars();
+
+    while let Some(c) = char_it.next() {
+        if c != '\\' {
+            let mut buffer = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+
+        let escaped = match char_it.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0x00,
+            Some('\\') => b'\\',
+            Some('\'') => b'\'',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = char_it.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(value) if hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) => value,
+                    _ => return Err(format!("invalid escape '\\x{}'", hex)),
+                }
+            }
+            Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+            None => return Err(String::from("unfinished escape")),
+        };
+        bytes.push(escaped);
+    }
+
+    Ok(bytes)
+}
@@ -17,135 +17,394 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+pub mod diagnostic;
+pub mod lexer;
+pub mod expression;
 pub mod instruction;
 pub mod code;
 pub mod glob;
 pub mod out_bin;
 pub mod context;
+pub mod symbols;
+pub mod disassembler;
+pub mod object;
+pub mod linker;
 
 #[cfg(test)]
 mod tests;
 
-use shard_core::opcodes::Opcode;
+use std::collections::HashMap;
 
-use crate::context::Context;
+use shard_core::opcodes::{Opcode, OperandKind};
+
+use crate::context::{Context, Section};
+use crate::diagnostic::{Diagnostic, Location, SourceLine};
+use crate::expression::Expression;
 use crate::glob::Glob;
 use crate::instruction::{Instruction, Literal};
+use crate::object::ObjectFile;
+use crate::symbols::SymbolMap;
 
 pub fn compile_from_asm(asm_source: Vec<String>) -> Result<Vec<u8>, String> {
+    compile(&SourceLine::from_lines(&asm_source, "")).map_err(|diagnostics| diagnostics[0].summary())
+}
+
+pub fn compile(source: &[SourceLine]) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    compile_with_symbols(source).map(|(bytes, _)| bytes)
+}
+
+// Compiles the program and reports all errors found in it
+pub fn compile_with_symbols(source: &[SourceLine]) -> Result<(Vec<u8>, SymbolMap), Vec<Diagnostic>> {
+    compile_with_defines(source, &[], false)
+}
+
+// Same as compile_with_symbols but the image starts with a jump to the entry point set with .entry,
+// for raw images that have no header to record it
+pub fn compile_with_entry_stub(source: &[SourceLine]) -> Result<(Vec<u8>, SymbolMap), Vec<Diagnostic>> {
+    compile_with_defines(source, &[], true)
+}
+
+// Defines are constants given outside of the source, e.g. with -D. They take precedence
+// over #define lines of the same name.
+pub fn compile_with_defines(source: &[SourceLine], defines: &[(String, i64)], entry_stub: bool) -> Result<(Vec<u8>, SymbolMap), Vec<Diagnostic>> {
+    let mut context = parse_program(source, defines)?;
+    if entry_stub {
+        context.add_entry_stub();
+    }
+    context.build_binary_with_symbols().map_err(|diagnostics| attach_sources(diagnostics, source))
+}
+
+// Compiles a module of the program, labels from other modules are resolved by the linker
+pub fn compile_object(source: &[SourceLine]) -> Result<ObjectFile, Vec<Diagnostic>> {
+    compile_object_with_defines(source, &[])
+}
+
+pub fn compile_object_with_defines(source: &[SourceLine], defines: &[(String, i64)]) -> Result<ObjectFile, Vec<Diagnostic>> {
+    let context = parse_program(source, defines)?;
+    context.build_object().map_err(|diagnostics| attach_sources(diagnostics, source))
+}
+
+fn parse_program(source: &[SourceLine], defines: &[(String, i64)]) -> Result<Context, Vec<Diagnostic>> {
     let mut context = Context::new();
+    let mut diagnostics = vec![];
+
+    for (index, (name, value)) in defines.iter().enumerate() {
+        if let Err(err) = context.define_constant(name, *value, Location::new("<command line>", index + 1)) {
+            diagnostics.push(Diagnostic::new(&err));
+        }
+    }
 
-    for line_number in 0..asm_source.len() {
-        parse_asm_line(&mut context, &asm_source[line_number], line_number + 1)?;
+    // Constants are defined first so that they can be used above their definition,
+    // e.g. in a module that imports the one defining them
+    for line in source.iter().filter(|line| is_constant_definition(&line.text)) {
+        if is_overridden_define(&line.text, defines) {
+            continue;
+        }
+        if let Err(diagnostic) = parse_source_line(&mut context, line) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    for line in source.iter().filter(|line| !is_constant_definition(&line.text)) {
+        if let Err(diagnostic) = parse_source_line(&mut context, line) {
+            diagnostics.push(diagnostic);
+        }
     }
 
-    context.write_binary()
+    match diagnostics.is_empty() {
+        true => Ok(context),
+        false => Err(diagnostics),
+    }
+}
+
+// Binary only knows spans, source lines have the text to show
+fn attach_sources(mut diagnostics: Vec<Diagnostic>, source: &[SourceLine]) -> Vec<Diagnostic> {
+    let lines: HashMap<&Location, &SourceLine> = source.iter().map(|line| (&line.location, line)).collect();
+    for diagnostic in diagnostics.iter_mut() {
+        if let Some(line) = diagnostic.span.as_ref().and_then(|span| lines.get(&span.location)) {
+            diagnostic.attach_source(line);
+        }
+    }
+    diagnostics
 }
 
 pub fn parse_asm_line(context: &mut Context, line: &str, line_number: usize) -> Result<(), String> {
-    let line = match line.find(";") {
-        None => line,
-        Some(delimiter) => line.split_at(delimiter).0
-    };
+    parse_source_line(context, &SourceLine::new(line, "", line_number)).map_err(|diagnostic| diagnostic.summary())
+}
 
-    let mut token_it = line.split_whitespace();
+pub fn parse_source_line(context: &mut Context, line: &SourceLine) -> Result<(), Diagnostic> {
+    let tokens = match lexer::tokenize_with_columns(&line.text) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            let span = line.full_span();
+            return Err(Diagnostic::at(&err, line, span.column, span.length))
+        }
+    };
+    let token_span = |index: usize| (tokens[index].0, tokens[index].1.chars().count());
+    // Span from the token until the end of the last one
+    let rest_span = |index: usize| {
+        let (last_column, last_length) = token_span(tokens.len() - 1);
+        (tokens[index].0, last_column + last_length - tokens[index].0)
+    };
+    let error = |message: String, (column, length): (usize, usize)| Diagnostic::at(&message, line, column, length);
 
-    let keyword = match token_it.next() {
-        Some(keyword) => keyword,
+    let keyword = match tokens.first() {
+        Some((_, keyword)) => keyword.as_str(),
         None => return Ok(())
     };
 
+    match keyword {
+        ".equ" | "#define" => return parse_constant(context, line, &tokens),
+        ".code" => {
+            context.set_section(Section::Code);
+            return Ok(());
+        }
+        ".data" => {
+            context.set_section(Section::Data);
+            return Ok(());
+        }
+        ".entry" => {
+            return match tokens.len() {
+                1 => Err(error(String::from("'.entry' is missing a label"), token_span(0))),
+                2 => {
+                    let span = line.span(token_span(1).0, token_span(1).1);
+                    context.set_entry(&tokens[1].1, Some(span)).map_err(|err| error(err, token_span(1)))
+                }
+                _ => Err(error(format!("unexpected operand '{}', '.entry' takes a single label", tokens[2].1), rest_span(2))),
+            };
+        }
+        _ => {}
+    }
+
     match Opcode::from_string(keyword) {
         None => {
             // label or global
             if keyword.ends_with(":") {
                 let mut label = String::from(keyword);
                 label.pop();
+                let label_span = line.span(tokens[0].0, label.chars().count());
                 let mut data = vec![];
+                let mut expressions = vec![];
+                let mut expression_spans = vec![];
+
+                for (index, (_, data_str)) in tokens.iter().enumerate().skip(1) {
+                    if data_str.starts_with('"') || data_str.starts_with("z\"") {
+                        match lexer::parse_data(data_str) {
+                            Ok(value) => data.extend(value),
+                            Err(err) => return Err(error(format!("failed to parse value '{}' - {}", data_str, err), token_span(index)))
+                        }
+                        continue;
+                    }
 
-                for data_str in token_it {
-                    let value_str_trimmed = data_str.trim_start_matches("0x");
-                    match u8::from_str_radix(value_str_trimmed, 16) {
-                        Ok(value) => data.push(value),
-                        Err(err) => return Err(String::from(format!("{}: failed to parse value '{}' - {}", line_number, data_str, err)))
+                    match parse_operand(context, data_str).and_then(|operand| operand.into_u8()) {
+                        Ok(Operand::Value(value)) => data.push(value as u8),
+                        Ok(Operand::Expression(expression)) => {
+                            let (column, length) = token_span(index);
+                            expressions.push((data.len(), expression));
+                            expression_spans.push(Some(line.span(column, length)));
+                            // Temporary value
+                            data.push(0x00);
+                        }
+                        Err(err) => return Err(error(format!("failed to parse value '{}' - {}", data_str, err), token_span(index)))
                     }
                 }
 
-                if data.is_empty() {
-                    let literal = Literal::Label(label);
-                    context.get_code_mut().push_instruction(Instruction::new_with_literal(Opcode::Label, literal));
+                let mut glob = if tokens.len() > 1 {
+                    Glob::new_with_expressions(label, data, expressions)
+                } else if context.get_section() == Section::Data {
+                    Glob::new(label)
                 } else {
-                    context.add_glob(Glob::new_with_value(label, data))?;
+                    if let Err(err) = context.define_label(&label, line.location.clone()) {
+                        return Err(Diagnostic::at(&err, line, label_span.column, label_span.length));
+                    }
+                    let literal = Literal::Label(label);
+                    let mut instruction = Instruction::new_with_literal(Opcode::Label, literal);
+                    instruction.set_span(label_span);
+                    context.get_code_mut().push_instruction(instruction);
+                    return Ok(());
+                };
+                glob.set_span(label_span.clone());
+                glob.set_expression_spans(expression_spans);
+                if let Err(err) = context.add_glob(glob) {
+                    return Err(Diagnostic::at(&err, line, label_span.column, label_span.length));
                 }
             } else {
-                return Err(String::from(format!("{}: Invalid keyword", line_number)))
+                return Err(error(String::from("Invalid keyword"), token_span(0)))
             }
 
         }
         Some(opcode) => {
-            match Opcode::is_opcode_instruction(opcode) {
-                true => {
-                    context.get_code_mut().push_instruction(Instruction::new(opcode));
+            if opcode.is_pseudo() {
+                return Err(error(format!("'{}' can't be used as an instruction, labels are written as 'name:'", keyword), token_span(0)));
+            }
+            if context.get_section() == Section::Data {
+                return Err(error(format!("instruction '{}' in .data section", keyword), token_span(0)));
+            }
+
+            let operand_kind = opcode.operand_kind();
+            if operand_kind == OperandKind::None {
+                if tokens.len() > 1 {
+                    return Err(error(format!("'{}' doesn't take an operand", keyword), rest_span(1)));
                 }
-                false => {
-                    let value_str = match token_it.next() {
-                        Some(value_str) => value_str,
-                        None => return Ok(())
-                    };
-
-                    match opcode {
-                        // label / u16
-                        Opcode::Call | Opcode::Jump | Opcode::PushAddr |
-                        Opcode::Load8 | Opcode::Load16 | Opcode::Store8 | Opcode::Store16 |
-                        Opcode::Eqz | Opcode::Eq | Opcode::Ne | Opcode::LtS | Opcode::LtU |
-                        Opcode::GtS | Opcode::GtU | Opcode::LeS | Opcode::LeU |
-                        Opcode::GeS | Opcode::GeU
-                        => {
-                            match value_str.starts_with("0x") {
-                                true => {
-                                    let value_str_trimmed = value_str.trim_start_matches("0x");
-                                    match u16::from_str_radix(value_str_trimmed, 16) {
-                                        Ok(value) => {
-                                            let literal = Literal::Address(value);
-                                            context.get_code_mut().push_instruction(Instruction::new_with_literal(opcode, literal));
-                                        },
-                                        Err(err) => {
-                                            return Err(String::from(format!("{}: failed to parse value '{}' - {}", line_number, value_str, err)))
-                                        }
-                                    }
-                                }
-                                false => {
-                                    let literal = Literal::Label(value_str.to_string());
-                                    context.get_code_mut().push_instruction(Instruction::new_with_literal(opcode, literal));
-                                }
-                            };
-                        }
-                        // u8
-                        Opcode::StackGet | Opcode::StackSet | Opcode::Push => {
-                            let value = match value_str.starts_with("0x") {
-                                true => {
-                                    let value_str_trimmed = value_str.trim_start_matches("0x");
-                                    match u8::from_str_radix(value_str_trimmed, 16) {
-                                        Ok(value) => value,
-                                        Err(err) => return Err(String::from(format!("{}: failed to parse value '{}' - {}", line_number, value_str, err)))
-                                    }
-                                }
-                                false => {
-                                    return Err(String::from(format!("{}: Value is not a hex number", line_number)))
-                                }
-                            };
-                            let literal = Literal::Const(value);
-                            context.get_code_mut().push_instruction(Instruction::new_with_literal(opcode, literal));
-                        }
-                        _ => {
-                            assert!(!Opcode::is_opcode_instruction(opcode));
-                        }
+                let mut instruction = Instruction::new(opcode);
+                let (column, length) = token_span(0);
+                instruction.set_span(line.span(column, length));
+                context.get_code_mut().push_instruction(instruction);
+                return Ok(());
+            }
+
+            if tokens.len() == 1 {
+                return Err(error(format!("'{}' is missing {}", keyword, operand_kind.describe()), token_span(0)));
+            }
+            let operand_end = 1 + operand_token_count(&tokens[1..]);
+            if operand_end < tokens.len() {
+                let extra = tokens[operand_end..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" ");
+                return Err(error(format!("unexpected operand '{}', '{}' takes a single operand", extra, keyword), rest_span(operand_end)));
+            }
+
+            // Operand expression can be split into several tokens
+            let value_str = tokens[1..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" ");
+            let operand_span = rest_span(1);
+            let is_string = value_str.starts_with('"') || value_str.starts_with("z\"");
+
+            let literal = match operand_kind {
+                OperandKind::U16 if is_string => {
+                    // String operand is placed into an anonymous global
+                    match lexer::parse_data(&value_str) {
+                        Ok(value) => Literal::Label(context.add_string_literal(value)),
+                        Err(err) => return Err(error(format!("failed to parse value '{}' - {}", value_str, err), operand_span))
                     }
                 }
-            }
+                OperandKind::U16 => {
+                    match parse_operand(context, &value_str) {
+                        Ok(Operand::Value(value)) => match lexer::value_to_u16(value) {
+                            Ok(value) => Literal::Address(value),
+                            Err(err) => return Err(error(format!("failed to parse value '{}' - {}", value_str, err), operand_span))
+                        },
+                        Ok(Operand::Expression(Expression::Symbol(label))) => Literal::Label(label),
+                        Ok(Operand::Expression(expression)) => Literal::Expression(expression),
+                        Err(err) => return Err(error(format!("failed to parse value '{}' - {}", value_str, err), operand_span))
+                    }
+                }
+                _ if is_string => {
+                    return Err(error(format!("'{}' expects {}, strings can only be used as addresses", keyword, operand_kind.describe()), operand_span))
+                }
+                _ => {
+                    match parse_operand(context, &value_str).and_then(|operand| operand.into_u8()) {
+                        Ok(Operand::Value(value)) => Literal::Const(value as u8),
+                        Ok(Operand::Expression(expression)) => Literal::Expression(expression),
+                        Err(err) => return Err(error(format!("failed to parse value '{}' - {}", value_str, err), operand_span))
+                    }
+                }
+            };
+
+            let mut instruction = Instruction::new_with_literal(opcode, literal);
+            instruction.set_span(line.span(operand_span.0, operand_span.1));
+            context.get_code_mut().push_instruction(instruction);
         }
     }
 
     Ok(())
 }
 
+// Operand value or an expression that refers to labels, which are known only when writing the binary
+enum Operand {
+    Value(i64),
+    Expression(Expression),
+}
+
+impl Operand {
+    // Checks that value fits into a byte. Label addresses are 16-bit so a plain label is not allowed.
+    fn into_u8(self) -> Result<Operand, String> {
+        match self {
+            Operand::Value(value) => Ok(Operand::Value(lexer::value_to_u8(value)? as i64)),
+            Operand::Expression(Expression::Symbol(name)) => {
+                Err(format!("'{}' is not a constant, label addresses can only be used with lo() or hi()", name))
+            }
+            Operand::Expression(expression) => Ok(Operand::Expression(expression)),
+        }
+    }
+}
+
+fn parse_operand(context: &Context, source: &str) -> Result<Operand, String> {
+    let expression = Expression::parse(source)?;
+    if expression.symbols().iter().all(|name| context.get_constant(name).is_some()) {
+        let value = expression.evaluate(&|name| context.get_constant(name))?;
+        Ok(Operand::Value(value))
+    } else {
+        Ok(Operand::Expression(expression))
+    }
+}
+
+// Number of tokens that make up the operand. When the tokens don't form a single expression but
+// split into two valid ones, the rest is an extra operand, e.g. 'push 0x01 0x02'.
+fn operand_token_count(tokens: &[(usize, String)]) -> usize {
+    let join = |tokens: &[(usize, String)]| tokens.iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" ");
+    let is_operand = |tokens: &[(usize, String)]| {
+        let source = join(tokens);
+        tokens.len() == 1 && (source.starts_with('"') || source.starts_with("z\"")) || Expression::parse(&source).is_ok()
+    };
+
+    if is_operand(tokens) {
+        return tokens.len();
+    }
+    (1..tokens.len())
+        .rev()
+        .find(|count| is_operand(&tokens[..*count]) && is_operand(&tokens[*count..]))
+        .unwrap_or(tokens.len())
+}
+
+fn is_constant_definition(line: &str) -> bool {
+    match lexer::tokenize(line) {
+        Ok(tokens) => tokens.first().is_some_and(|keyword| keyword == ".equ" || keyword == "#define"),
+        Err(_) => false,
+    }
+}
+
+// #define of a name given outside of the source is ignored, .equ of it is still a redefinition
+fn is_overridden_define(line: &str, defines: &[(String, i64)]) -> bool {
+    match lexer::tokenize(line) {
+        Ok(tokens) => tokens.len() > 1 && tokens[0] == "#define" && defines.iter().any(|(name, _)| *name == tokens[1]),
+        Err(_) => false,
+    }
+}
+
+// .equ NAME value
+// #define NAME [value] - value defaults to 1
+fn parse_constant(context: &mut Context, line: &SourceLine, tokens: &[(usize, String)]) -> Result<(), Diagnostic> {
+    let keyword = tokens[0].1.as_str();
+    let (name_column, name) = match tokens.get(1) {
+        Some((column, name)) => (*column, name.as_str()),
+        None => return Err(Diagnostic::at(&format!("{} is missing constant name", keyword), line, tokens[0].0, keyword.len()))
+    };
+    let name_length = name.chars().count();
+    if lexer::is_literal(name) || name.ends_with(':') || Opcode::from_string(name).is_some() {
+        return Err(Diagnostic::at(&format!("invalid constant name '{}'", name), line, name_column, name_length));
+    }
+
+    let value_str = tokens[2..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" ");
+    let value_span = match tokens.last() {
+        Some((column, token)) if tokens.len() > 2 => (tokens[2].0, column + token.chars().count() - tokens[2].0),
+        _ => (name_column, name_length),
+    };
+    let value = match parse_operand(context, &value_str) {
+        _ if value_str.is_empty() && keyword == "#define" => 1,
+        _ if value_str.is_empty() => {
+            return Err(Diagnostic::at(&format!(".equ is missing value for '{}'", name), line, name_column, name_length))
+        }
+        Ok(Operand::Value(value)) => value,
+        Ok(Operand::Expression(_)) => {
+            let message = format!("value of '{}' can only use constants defined above it", name);
+            return Err(Diagnostic::at(&message, line, value_span.0, value_span.1))
+        }
+        Err(err) => {
+            let message = format!("failed to parse value '{}' - {}", value_str, err);
+            return Err(Diagnostic::at(&message, line, value_span.0, value_span.1))
+        }
+    };
+
+    match context.define_constant(name, value, line.location.clone()) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Diagnostic::at(&err, line, name_column, name_length))
+    }
+}
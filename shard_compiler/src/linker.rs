@@ -0,0 +1,132 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::collections::HashMap;
+
+use crate::context::Section;
+use crate::diagnostic::Diagnostic;
+use crate::lexer;
+use crate::object::ObjectFile;
+use crate::symbols::SymbolMap;
+
+// Combines objects into an image. Code sections come first in the order of objects and data
+// sections follow them, so the first object's code starts at 0x00. Only one object can set the
+// entry point. Object names are used in errors.
+pub fn link(objects: &[(String, ObjectFile)]) -> Result<(Vec<u8>, SymbolMap), Vec<Diagnostic>> {
+    let code_size: usize = objects.iter().map(|(_, object)| object.code.len()).sum();
+    let data_size: usize = objects.iter().map(|(_, object)| object.data.len()).sum();
+    if code_size + data_size > u16::MAX as usize + 1 {
+        return Err(vec![Diagnostic::new(&format!("linked image of {} bytes doesn't fit into memory", code_size + data_size))]);
+    }
+
+    // Start of code and data sections of every object
+    let mut bases = vec![];
+    let mut image = vec![];
+    for (_, object) in objects.iter() {
+        bases.push((image.len(), 0));
+        image.extend_from_slice(&object.code);
+    }
+    for (index, (_, object)) in objects.iter().enumerate() {
+        bases[index].1 = image.len();
+        image.extend_from_slice(&object.data);
+    }
+    let address_of = |index: usize, section: Section, offset: u16| match section {
+        Section::Code => bases[index].0 + offset as usize,
+        Section::Data => bases[index].1 + offset as usize,
+    };
+
+    let mut diagnostics = vec![];
+    let mut symbols = SymbolMap::new(code_size as u16);
+    // Name to address and the object that defines it
+    let mut globals: HashMap<&str, (u16, usize)> = HashMap::new();
+    for (index, (object_name, object)) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter() {
+            let address = address_of(index, symbol.section, symbol.offset) as u16;
+            symbols.add_symbol(&symbol.name, address, symbol.kind);
+            if symbol.is_local() {
+                continue;
+            }
+            match globals.get(symbol.name.as_str()) {
+                Some((_, other)) => diagnostics.push(Diagnostic::new(&format!(
+                    "symbol '{}' is defined in both {} and {}", symbol.name, objects[*other].0, object_name
+                ))),
+                None => {
+                    globals.insert(&symbol.name, (address, index));
+                }
+            }
+        }
+    }
+
+    for (index, (_, object)) in objects.iter().enumerate() {
+        for mapping in object.lines.iter() {
+            symbols.add_line(address_of(index, Section::Code, mapping.address) as u16, &mapping.file, mapping.line);
+        }
+    }
+
+    for (index, (object_name, object)) in objects.iter().enumerate() {
+        // Local symbols of the object take precedence
+        let resolve = |name: &str| match object.get_symbol(name).filter(|symbol| symbol.is_local()) {
+            Some(symbol) => Some(address_of(index, symbol.section, symbol.offset) as i64),
+            None => globals.get(name).map(|(address, _)| *address as i64),
+        };
+
+        for relocation in object.relocations.iter() {
+            let section_size = match relocation.section {
+                Section::Code => object.code.len(),
+                Section::Data => object.data.len(),
+            };
+            if relocation.offset as usize + relocation.width as usize > section_size {
+                diagnostics.push(Diagnostic::new(&format!("relocation at 0x{:04x} is outside of its section in {}", relocation.offset, object_name)));
+                continue;
+            }
+
+            if let Some(name) = relocation.expression.symbols().into_iter().find(|name| resolve(name).is_none()) {
+                diagnostics.push(Diagnostic::new(&format!("undefined symbol '{}' referenced in {}", name, object_name)));
+                continue;
+            }
+            let position = address_of(index, relocation.section, relocation.offset);
+            let result = relocation.expression.evaluate(&resolve).and_then(|value| match relocation.width {
+                1 => lexer::label_value_to_u8(value).map(|value| vec![value]),
+                _ => lexer::label_value_to_u16(value).map(|value| value.to_le_bytes().to_vec()),
+            });
+            match result {
+                Ok(bytes) => image[position..position + bytes.len()].copy_from_slice(&bytes),
+                Err(err) => diagnostics.push(Diagnostic::new(&format!("{} in {}", err, object_name))),
+            }
+        }
+    }
+
+    let mut entry_objects = objects.iter().filter(|(_, object)| object.entry.is_some());
+    if let Some((object_name, object)) = entry_objects.next() {
+        let name = object.entry.as_deref().unwrap_or_default();
+        if let Some((other_name, _)) = entry_objects.next() {
+            diagnostics.push(Diagnostic::new(&format!("entry point is set in both {} and {}", object_name, other_name)));
+        }
+        match globals.get(name) {
+            Some((address, _)) if (*address as usize) < code_size => symbols.entry = Some(*address),
+            Some(_) => diagnostics.push(Diagnostic::new(&format!("entry point '{}' of {} is not in the code", name, object_name))),
+            None => diagnostics.push(Diagnostic::new(&format!("undefined entry point '{}' in {}", name, object_name))),
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok((image, symbols))
+}
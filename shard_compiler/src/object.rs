@@ -0,0 +1,241 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::context::{Section, SymbolKind};
+use crate::expression::Expression;
+use crate::symbols::LineMapping;
+
+const MAGIC: &[u8; 4] = b"SHOB";
+const VERSION: u8 = 1;
+
+// Symbol defined by the object, offset is from the start of its section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub section: Section,
+    pub offset: u16,
+}
+
+impl ObjectSymbol {
+    // Names starting with '.' are generated by the assembler, e.g. string literals and
+    // local labels of macros, and are only visible inside their object
+    pub fn is_local(&self) -> bool {
+        self.name.starts_with('.')
+    }
+}
+
+// Bytes at the offset of the section that are patched with the value of the expression once
+// symbol addresses are known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub section: Section,
+    pub offset: u16,
+    // 1 or 2 bytes, 16-bit values are little-endian
+    pub width: u8,
+    pub expression: Expression,
+}
+
+// Code and data of a separately compiled module. Linker places all code sections
+// before all data sections and resolves relocations.
+//
+// Layout, numbers are little-endian:
+//   "SHOB" version:u8
+//   code_size:u16 code  data_size:u16 data
+//   symbol_count:u16 [kind:u8 section:u8 offset:u16 name]
+//   relocation_count:u16 [section:u8 offset:u16 width:u8 expression]
+//   entry
+//   line_count:u16 [offset:u16 line:u32 file]
+// where name, expression, entry and file are strings stored as length:u16 followed by UTF-8 bytes.
+// Entry is empty when the module doesn't set it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+    // Label set with .entry
+    pub entry: Option<String>,
+    // Source lines of the instructions, addresses are offsets in the code section
+    pub lines: Vec<LineMapping>,
+}
+
+impl ObjectFile {
+    pub fn new() -> ObjectFile {
+        ObjectFile::default()
+    }
+
+    pub fn get_symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_block(&mut bytes, &self.code);
+        write_block(&mut bytes, &self.data);
+
+        write_u16(&mut bytes, self.symbols.len() as u16);
+        for symbol in self.symbols.iter() {
+            bytes.push(match symbol.kind {
+                SymbolKind::Glob => 1,
+                _ => 0,
+            });
+            bytes.push(section_to_u8(symbol.section));
+            write_u16(&mut bytes, symbol.offset);
+            write_block(&mut bytes, symbol.name.as_bytes());
+        }
+
+        write_u16(&mut bytes, self.relocations.len() as u16);
+        for relocation in self.relocations.iter() {
+            bytes.push(section_to_u8(relocation.section));
+            write_u16(&mut bytes, relocation.offset);
+            bytes.push(relocation.width);
+            write_block(&mut bytes, relocation.expression.to_string().as_bytes());
+        }
+        write_block(&mut bytes, self.entry.as_deref().unwrap_or("").as_bytes());
+
+        write_u16(&mut bytes, self.lines.len() as u16);
+        for mapping in self.lines.iter() {
+            write_u16(&mut bytes, mapping.address);
+            bytes.extend_from_slice(&(mapping.line as u32).to_le_bytes());
+            write_block(&mut bytes, mapping.file.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.read(MAGIC.len())? != MAGIC {
+            return Err(String::from("not a shard object file"));
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported object file version {}", version));
+        }
+
+        let mut object = ObjectFile::new();
+        object.code = reader.read_block()?.to_vec();
+        object.data = reader.read_block()?.to_vec();
+
+        for _ in 0..reader.read_u16()? {
+            let kind = match reader.read_u8()? {
+                0 => SymbolKind::Label,
+                1 => SymbolKind::Glob,
+                kind => return Err(format!("invalid symbol kind {}", kind)),
+            };
+            let section = reader.read_section()?;
+            let offset = reader.read_u16()?;
+            let name = reader.read_string()?;
+            object.symbols.push(ObjectSymbol { name, kind, section, offset });
+        }
+
+        for _ in 0..reader.read_u16()? {
+            let section = reader.read_section()?;
+            let offset = reader.read_u16()?;
+            let width = reader.read_u8()?;
+            if width != 1 && width != 2 {
+                return Err(format!("invalid relocation width {}", width));
+            }
+            let expression = Expression::parse(&reader.read_string()?)?;
+            object.relocations.push(Relocation { section, offset, width, expression });
+        }
+
+        object.entry = Some(reader.read_string()?).filter(|entry| !entry.is_empty());
+
+        for _ in 0..reader.read_u16()? {
+            let address = reader.read_u16()?;
+            let line = reader.read_u32()? as usize;
+            let file = reader.read_string()?;
+            object.lines.push(LineMapping { address, file, line });
+        }
+
+        if reader.position != bytes.len() {
+            return Err(String::from("unexpected data at the end of object file"));
+        }
+        Ok(object)
+    }
+}
+
+fn section_to_u8(section: Section) -> u8 {
+    match section {
+        Section::Code => 0,
+        Section::Data => 1,
+    }
+}
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_block(bytes: &mut Vec<u8>, block: &[u8]) {
+    write_u16(bytes, block.len() as u16);
+    bytes.extend_from_slice(block);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, size: usize) -> Result<&'a [u8], String> {
+        match self.bytes.get(self.position..self.position + size) {
+            Some(bytes) => {
+                self.position += size;
+                Ok(bytes)
+            }
+            None => Err(String::from("object file is truncated")),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.read(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        let bytes = self.read(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_block(&mut self) -> Result<&'a [u8], String> {
+        let size = self.read_u16()?;
+        self.read(size as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        match String::from_utf8(self.read_block()?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(String::from("invalid string in object file")),
+        }
+    }
+
+    fn read_section(&mut self) -> Result<Section, String> {
+        match self.read_u8()? {
+            0 => Ok(Section::Code),
+            1 => Ok(Section::Data),
+            section => Err(format!("invalid section {}", section)),
+        }
+    }
+}
@@ -21,10 +21,25 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 
+use crate::diagnostic::{Location, Span};
+use crate::expression::Expression;
+
 pub struct OutBin {
     pub code: Vec<u8>,
     pub address_table: HashMap<String, u16>,
     pub addresses_to_update: HashMap<u16, String>,
+    // Offset to expression and its width in bytes
+    pub expressions_to_update: HashMap<u16, (Expression, usize)>,
+    // Source of the values to update
+    pub spans: HashMap<u16, Span>,
+    // Source line of every encoded instruction, in the order of addresses
+    pub lines: Vec<(u16, Location)>,
+}
+
+impl Default for OutBin {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OutBin {
@@ -33,6 +48,15 @@ impl OutBin {
             code: vec![],
             address_table: HashMap::new(),
             addresses_to_update: HashMap::new(),
+            expressions_to_update: HashMap::new(),
+            spans: HashMap::new(),
+            lines: vec![],
+        }
+    }
+
+    pub fn add_span(&mut self, offset: u16, span: Option<&Span>) {
+        if let Some(span) = span {
+            self.spans.insert(offset, span.clone());
         }
     }
 
@@ -46,7 +70,7 @@ impl OutBin {
     }
 
     fn write_bytes(file: &mut File, bytes: &[u8]) -> Result<(), String> {
-        match file.write(bytes) {
+        match file.write_all(bytes) {
             Ok(_) => Ok(()),
             Err(_) => Err(String::from("Failed to write bytes"))
         }
@@ -0,0 +1,224 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use crate::context::SymbolKind;
+use crate::lexer;
+
+// Label or global placed in the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedSymbol {
+    pub name: String,
+    pub address: u16,
+    pub kind: SymbolKind,
+}
+
+// Source line an instruction was encoded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMapping {
+    pub address: u16,
+    // Empty when the source didn't come from a file
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for LineMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file.is_empty() {
+            true => write!(f, "line {}", self.line),
+            false => write!(f, "{}:{}", self.file, self.line),
+        }
+    }
+}
+
+// Addresses of the symbols and source lines of the instructions in a compiled image.
+// Saved as text, one entry per line:
+//   code_size 0x0012
+//   entry 0x0000
+//   label 0x0000 main
+//   global 0x0012 hello_world
+//   line 0x0000 main.srd:4
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    // Global data starts right after the code
+    pub code_size: u16,
+    // Set with .entry, otherwise execution starts at 0x00
+    pub entry: Option<u16>,
+    // Sorted by address
+    pub symbols: Vec<MappedSymbol>,
+    // Sorted by address
+    pub lines: Vec<LineMapping>,
+}
+
+impl SymbolMap {
+    pub fn new(code_size: u16) -> SymbolMap {
+        SymbolMap { code_size, entry: None, symbols: vec![], lines: vec![] }
+    }
+
+    pub fn add_symbol(&mut self, name: &str, address: u16, kind: SymbolKind) {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        self.symbols.insert(index, MappedSymbol { name: name.to_string(), address, kind });
+    }
+
+    pub fn add_line(&mut self, address: u16, file: &str, line: usize) {
+        let index = self.lines.partition_point(|mapping| mapping.address <= address);
+        self.lines.insert(index, LineMapping { address, file: file.to_string(), line });
+    }
+
+    // Line of the instruction the address belongs to
+    pub fn line_at(&self, address: u16) -> Option<&LineMapping> {
+        if address >= self.code_size {
+            return None;
+        }
+        let index = self.lines.partition_point(|mapping| mapping.address <= address);
+        index.checked_sub(1).map(|index| &self.lines[index])
+    }
+
+    // Closest code label at or before the address, e.g. the function it's in
+    pub fn label_before(&self, address: u16) -> Option<&MappedSymbol> {
+        if address >= self.code_size {
+            return None;
+        }
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        self.symbols[..index].iter().rev().find(|symbol| symbol.kind == SymbolKind::Label)
+    }
+
+    pub fn get_address(&self, name: &str) -> Option<u16> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address)
+    }
+
+    pub fn symbols_at(&self, address: u16) -> impl Iterator<Item = &MappedSymbol> {
+        let start = self.symbols.partition_point(|symbol| symbol.address < address);
+        self.symbols[start..].iter().take_while(move |symbol| symbol.address == address)
+    }
+
+    // Name an address operand refers to, see preferred_name
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        let start = self.symbols.partition_point(|symbol| symbol.address < address);
+        let end = self.symbols.partition_point(|symbol| symbol.address <= address);
+        preferred_name(&self.symbols[start..end])
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("code_size 0x{:04x}\n", self.code_size);
+        if let Some(entry) = self.entry {
+            text.push_str(&format!("entry 0x{:04x}\n", entry));
+        }
+        for symbol in self.symbols.iter() {
+            let kind = match symbol.kind {
+                SymbolKind::Glob => "global",
+                _ => "label",
+            };
+            text.push_str(&format!("{} 0x{:04x} {}\n", kind, symbol.address, symbol.name));
+        }
+        for mapping in self.lines.iter() {
+            text.push_str(&format!("line 0x{:04x} {}:{}\n", mapping.address, mapping.file, mapping.line));
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<SymbolMap, String> {
+        let mut map = SymbolMap::default();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                [] => {}
+                ["code_size", size] => map.code_size = parse_address(size, line_number)?,
+                ["entry", address] => map.entry = Some(parse_address(address, line_number)?),
+                [kind @ ("label" | "global"), address, name] => {
+                    let kind = match *kind {
+                        "global" => SymbolKind::Glob,
+                        _ => SymbolKind::Label,
+                    };
+                    map.add_symbol(name, parse_address(address, line_number)?, kind);
+                }
+                ["line", address, ..] => {
+                    // File name can have spaces, line number is after the last ':'
+                    let source = line.trim().splitn(3, char::is_whitespace).nth(2).unwrap_or_default();
+                    let mapping = source.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?)));
+                    match mapping {
+                        Some((file, line)) => map.add_line(parse_address(address, line_number)?, file, line),
+                        None => return Err(format!("{}: invalid source line '{}'", line_number, source)),
+                    }
+                }
+                _ => return Err(format!("{}: invalid symbol entry '{}'", line_number, line.trim())),
+            }
+        }
+        Ok(map)
+    }
+}
+
+// Names of the addresses of an image for the disassembler, the debugger and decoding of images:
+// symbols of the symbol map and generated labels of branch targets that have no name
+#[derive(Debug, Clone, Default)]
+pub struct AddressNames {
+    names: BTreeMap<u16, Vec<MappedSymbol>>,
+}
+
+impl AddressNames {
+    // Symbols past the end of the image are dropped. Name that is used twice, e.g. local symbol
+    // of linked objects, is kept at its first address.
+    pub fn new(symbols: Option<&SymbolMap>, image_size: usize) -> AddressNames {
+        let mut names: BTreeMap<u16, Vec<MappedSymbol>> = BTreeMap::new();
+        let mut used_names = HashSet::new();
+        for symbol in symbols.iter().flat_map(|symbols| symbols.symbols.iter()) {
+            if (symbol.address as usize) <= image_size && used_names.insert(symbol.name.as_str()) {
+                names.entry(symbol.address).or_default().push(symbol.clone());
+            }
+        }
+        AddressNames { names }
+    }
+
+    // Address without a name gets label_XXXX
+    pub fn add_label(&mut self, address: u16) {
+        self.names.entry(address).or_insert_with(|| {
+            vec![MappedSymbol { name: format!("label_{:04x}", address), address, kind: SymbolKind::Label }]
+        });
+    }
+
+    pub fn names_at(&self, address: u16) -> &[MappedSymbol] {
+        self.names.get(&address).map_or(&[], |names| names.as_slice())
+    }
+
+    // Name an address operand refers to, see preferred_name
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        preferred_name(self.names_at(address))
+    }
+
+    // Named addresses in order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[MappedSymbol])> {
+        self.names.iter().map(|(address, names)| (*address, names.as_slice()))
+    }
+}
+
+// Global is preferred over a label at the end of the code, the operand points to its data.
+// Otherwise it's the first symbol defined at the address.
+fn preferred_name(symbols: &[MappedSymbol]) -> Option<&str> {
+    let glob = symbols.iter().find(|symbol| symbol.kind == SymbolKind::Glob);
+    glob.or(symbols.first()).map(|symbol| symbol.name.as_str())
+}
+
+fn parse_address(value: &str, line_number: usize) -> Result<u16, String> {
+    lexer::parse_value(value)
+        .and_then(lexer::value_to_u16)
+        .map_err(|err| format!("{}: invalid address '{}' - {}", line_number, value, err))
+}
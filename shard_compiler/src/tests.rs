@@ -18,7 +18,14 @@
 //
 
 use shard_core::opcodes::Opcode;
-use crate::{Context, Literal, parse_asm_line};
+use crate::{Context, Literal, parse_asm_line, compile_from_asm, compile, compile_with_symbols, compile_with_entry_stub, compile_with_defines, compile_object};
+use crate::context::{Section, SymbolKind};
+use crate::disassembler::{DecodedInstruction, decode_instruction, disassemble, format_instruction};
+use crate::linker::link;
+use crate::object::{ObjectFile, Relocation};
+use crate::symbols::{AddressNames, SymbolMap};
+use crate::diagnostic::{Location, SourceLine};
+use crate::expression::Expression;
 
 #[test]
 fn compile_from_string() {
@@ -127,47 +134,784 @@ fn compile_from_string() {
     {
         let mut context = Context::new();
 
-        match parse_asm_line(&mut context, "jump 0x12346", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "push 0x123", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "push asdasd", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "another_glob: 0x01, 0x001", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "another_glob: 0x01 0x100", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "another_glob: 0x01 100", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-        match parse_asm_line(&mut context, "another_glob: 0x01 booooom", 1) {
-            Ok(_) => {
-                assert!(false);
-            }
-            Err(_) => {}
-        };
-    }
-}
\ No newline at end of file
+        assert!(parse_asm_line(&mut context, "jump 0x12346", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 0x123", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push asdasd", 1).is_err());
+        assert!(parse_asm_line(&mut context, "another_glob: 0x01, 0x001", 1).is_err());
+        assert!(parse_asm_line(&mut context, "another_glob: 0x01 0x100", 1).is_err());
+        assert!(parse_asm_line(&mut context, "another_glob: 0x01 300", 1).is_err());
+        assert!(parse_asm_line(&mut context, "another_glob: 0x01 booooom", 1).is_err());
+    }
+}
+
+#[test]
+fn literal_tests() {
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, "push 10", 1).unwrap();
+        parse_asm_line(&mut context, "push 0b1010", 1).unwrap();
+        parse_asm_line(&mut context, "push -1", 1).unwrap();
+        parse_asm_line(&mut context, "push -128", 1).unwrap();
+        parse_asm_line(&mut context, "push 255", 1).unwrap();
+        parse_asm_line(&mut context, "push 'A'", 1).unwrap();
+        parse_asm_line(&mut context, "push ' '", 1).unwrap();
+        parse_asm_line(&mut context, "push ';' ; char literal isn't a comment", 1).unwrap();
+        parse_asm_line(&mut context, "push '\\n'", 1).unwrap();
+        parse_asm_line(&mut context, "stack_get 0x01", 1).unwrap();
+
+        let values: Vec<&Literal> = context.get_code().get_code().iter().map(|instruction| instruction.get_literal()).collect();
+        assert_eq!(values, vec![
+            &Literal::Const(10), &Literal::Const(0b1010), &Literal::Const(0xff), &Literal::Const(0x80),
+            &Literal::Const(255), &Literal::Const(b'A'), &Literal::Const(b' '), &Literal::Const(b';'),
+            &Literal::Const(b'\n'), &Literal::Const(0x01),
+        ]);
+    }
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, "jump 4660", 1).unwrap();
+        parse_asm_line(&mut context, "push_addr -2", 1).unwrap();
+        parse_asm_line(&mut context, "load8 0b1000000000000000", 1).unwrap();
+        parse_asm_line(&mut context, "store8 65535", 1).unwrap();
+
+        let values: Vec<&Literal> = context.get_code().get_code().iter().map(|instruction| instruction.get_literal()).collect();
+        assert_eq!(values, vec![
+            &Literal::Address(0x1234), &Literal::Address(0xfffe), &Literal::Address(0x8000), &Literal::Address(0xffff),
+        ]);
+    }
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, "text: \"Hi; there\" 10 'x' -1 0b11", 1).unwrap();
+        parse_asm_line(&mut context, "escaped: \"\\t\\\"\\\\\\x41\\0\"", 1).unwrap();
+        parse_asm_line(&mut context, "terminated: z\"ok\"", 1).unwrap();
+        parse_asm_line(&mut context, "empty: \"\"", 1).unwrap();
+
+        let globs = context.get_globs();
+        assert_eq!(globs.len(), 4);
+        assert_eq!(globs[0].get_value(), &b"Hi; there\nx\xff\x03".to_vec());
+        assert_eq!(globs[1].get_value(), &b"\t\"\\A\0".to_vec());
+        assert_eq!(globs[2].get_value(), &b"ok\0".to_vec());
+        assert_eq!(globs[3].get_name(), "empty");
+        assert!(globs[3].get_value().is_empty());
+        assert!(context.get_code().get_code().is_empty());
+    }
+    {
+        // String operands become anonymous globals
+        let bin = compile_from_asm(vec![
+            String::from("main:"),
+            String::from("    push_addr z\"ab\""),
+            String::from("    return"),
+        ]).unwrap();
+        assert_eq!(bin, vec![Opcode::PushAddr as u8, 0x04, 0x00, Opcode::Return as u8, b'a', b'b', 0x00]);
+    }
+    {
+        let mut context = Context::new();
+
+        assert!(parse_asm_line(&mut context, "push 256", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push -129", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 0b102", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 0x-1", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 'ab'", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 'a", 1).is_err());
+        assert!(parse_asm_line(&mut context, "push 12ab", 1).is_err());
+        assert!(parse_asm_line(&mut context, "jump 65536", 1).is_err());
+        assert!(parse_asm_line(&mut context, "jump -32769", 1).is_err());
+        assert!(parse_asm_line(&mut context, "glob: \"unterminated", 1).is_err());
+        assert!(parse_asm_line(&mut context, "glob: \"bad \\q escape\"", 1).is_err());
+        assert!(parse_asm_line(&mut context, "glob: \"\\x4\"", 1).is_err());
+        assert!(context.get_globs().is_empty());
+    }
+}
+
+#[test]
+fn constant_tests() {
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, ".equ SYS_WRITE 0x01", 1).unwrap();
+        parse_asm_line(&mut context, ".equ BUFFER 0x8000 ; ram", 2).unwrap();
+        parse_asm_line(&mut context, "#define MINUS_ONE -1", 3).unwrap();
+        parse_asm_line(&mut context, "#define FLAG", 4).unwrap();
+        parse_asm_line(&mut context, ".equ ALIAS SYS_WRITE", 5).unwrap();
+
+        parse_asm_line(&mut context, "push SYS_WRITE", 6).unwrap();
+        parse_asm_line(&mut context, "push_addr BUFFER", 7).unwrap();
+        parse_asm_line(&mut context, "push MINUS_ONE", 8).unwrap();
+        parse_asm_line(&mut context, "push_addr MINUS_ONE", 9).unwrap();
+        parse_asm_line(&mut context, "stack_get FLAG", 10).unwrap();
+        parse_asm_line(&mut context, "push ALIAS", 11).unwrap();
+        parse_asm_line(&mut context, "call not_a_constant", 12).unwrap();
+        parse_asm_line(&mut context, "data: SYS_WRITE MINUS_ONE 0x02", 13).unwrap();
+
+        let values: Vec<&Literal> = context.get_code().get_code().iter().map(|instruction| instruction.get_literal()).collect();
+        assert_eq!(values, vec![
+            &Literal::Const(0x01), &Literal::Address(0x8000), &Literal::Const(0xff), &Literal::Address(0xffff),
+            &Literal::Const(0x01), &Literal::Const(0x01), &Literal::Label(String::from("not_a_constant")),
+        ]);
+        assert_eq!(context.get_globs()[0].get_value(), &vec![0x01, 0xff, 0x02]);
+    }
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, ".equ SIZE 0x40", 3).unwrap();
+        parse_asm_line(&mut context, ".equ BIG 0x1234", 4).unwrap();
+
+        let err = parse_asm_line(&mut context, ".equ SIZE 0x41", 7).unwrap_err();
+        assert_eq!(err, "7: constant 'SIZE' is already defined at line 3");
+        assert!(parse_asm_line(&mut context, "#define SIZE", 8).is_err());
+        assert!(parse_asm_line(&mut context, "push BIG", 9).is_err());
+        assert!(parse_asm_line(&mut context, "glob: BIG", 9).is_err());
+        assert!(parse_asm_line(&mut context, "push UNDEFINED", 10).is_err());
+        assert!(parse_asm_line(&mut context, ".equ", 11).is_err());
+        assert!(parse_asm_line(&mut context, ".equ NO_VALUE", 12).is_err());
+        assert!(parse_asm_line(&mut context, ".equ 0x10 0x10", 13).is_err());
+        assert!(parse_asm_line(&mut context, ".equ push 0x10", 14).is_err());
+        assert!(parse_asm_line(&mut context, ".equ BAD asdasd", 15).is_err());
+        assert!(parse_asm_line(&mut context, ".equ EXTRA 0x01 0x02", 16).is_err());
+    }
+    {
+        // Constants can be used before they are defined when compiling whole program
+        let bin = compile_from_asm(vec![
+            String::from("main:"),
+            String::from("    push SYS_EXIT"),
+            String::from("    return"),
+            String::from(".equ SYS_EXIT 0x07"),
+        ]).unwrap();
+        assert_eq!(bin, vec![Opcode::Push as u8, 0x07, Opcode::Return as u8]);
+
+        let err = compile_from_asm(vec![
+            String::from(".equ A 0x01"),
+            String::from("main:"),
+            String::from(".equ A 0x02"),
+        ]).unwrap_err();
+        assert_eq!(err, "3: constant 'A' is already defined at line 1");
+    }
+    {
+        // Defines given outside of the source take precedence over #define
+        let source = SourceLine::from_lines(&[
+            String::from("#define LEVEL 0x01"),
+            String::from("push LEVEL"),
+            String::from("push DEBUG"),
+        ], "");
+        let defines = [(String::from("LEVEL"), 0x05), (String::from("DEBUG"), 0x01)];
+        let (bin, _) = compile_with_defines(&source, &defines, false).unwrap();
+        assert_eq!(bin, vec![Opcode::Push as u8, 0x05, Opcode::Push as u8, 0x01]);
+
+        let mut source = source;
+        source.push(SourceLine::new(".equ DEBUG 0x02", "", 4));
+        let diagnostics = compile_with_defines(&source, &defines, false).unwrap_err();
+        assert_eq!(diagnostics[0].summary(), "4: constant 'DEBUG' is already defined at <command line>:2");
+    }
+}
+
+#[test]
+fn expression_tests() {
+    {
+        let no_symbols = |_: &str| None;
+        let evaluate = |source: &str| Expression::parse(source).and_then(|expression| expression.evaluate(&no_symbols));
+
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9));
+        assert_eq!(evaluate("10 - 2 - 3"), Ok(5));
+        assert_eq!(evaluate("10 / 3"), Ok(3));
+        assert_eq!(evaluate("1 << 4 | 1"), Ok(17));
+        assert_eq!(evaluate("0x100 >> 4"), Ok(0x10));
+        assert_eq!(evaluate("0xff & 0x0f | 0b10000"), Ok(0x1f));
+        assert_eq!(evaluate("1 + 2 << 1"), Ok(6));
+        assert_eq!(evaluate("-(2 + 3)"), Ok(-5));
+        assert_eq!(evaluate("'a' + 1"), Ok(0x62));
+        assert_eq!(evaluate("lo(0x1234)"), Ok(0x34));
+        assert_eq!(evaluate("hi(0x1234) + 1"), Ok(0x13));
+
+        // Comparisons and logical operators evaluate to 1 or 0
+        assert_eq!(evaluate("2 == 1 + 1"), Ok(1));
+        assert_eq!(evaluate("2 != 1 + 1"), Ok(0));
+        assert_eq!(evaluate("1 < 2"), Ok(1));
+        assert_eq!(evaluate("1 > 2"), Ok(0));
+        assert_eq!(evaluate("2 <= 2"), Ok(1));
+        assert_eq!(evaluate("1 >= 2"), Ok(0));
+        assert_eq!(evaluate("!0"), Ok(1));
+        assert_eq!(evaluate("!5"), Ok(0));
+        assert_eq!(evaluate("1 && 2"), Ok(1));
+        assert_eq!(evaluate("1 && 0"), Ok(0));
+        assert_eq!(evaluate("0 || 3"), Ok(1));
+        assert_eq!(evaluate("0 || 0"), Ok(0));
+        assert_eq!(evaluate("1 < 2 && 2 < 1 || 3 == 3"), Ok(1));
+        assert_eq!(evaluate("1 << 2 >= 4"), Ok(1));
+
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("1 << 64").is_err());
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 + 2)").is_err());
+        assert!(evaluate("1 2").is_err());
+        assert!(evaluate("1 = 2").is_err());
+        assert!(evaluate("1 <").is_err());
+        assert!(evaluate("1 % 2").is_err());
+        assert!(evaluate("lo()").is_err());
+        assert!(evaluate("label").is_err());
+
+        // Overflowing i64 is an error, not a panic
+        let overflow = Err(String::from("expression overflow"));
+        assert_eq!(evaluate("-(-9223372036854775807 - 1)"), overflow);
+        assert_eq!(evaluate("9223372036854775807 + 1"), overflow);
+        assert_eq!(evaluate("-9223372036854775807 - 2"), overflow);
+        assert_eq!(evaluate("9223372036854775807 * 2"), overflow);
+        assert_eq!(evaluate("-(-9223372036854775807)"), Ok(9223372036854775807));
+    }
+    {
+        let bin = compile_from_asm(vec![
+            String::from(".equ SYS 0x01"),
+            String::from("main:"),
+            String::from("    push msg_end - msg"),
+            String::from("    push_addr msg + 1"),
+            String::from("    push lo(msg)"),
+            String::from("    push hi(msg)"),
+            String::from("    push SYS * 2 + 1"),
+            String::from("    jump main"),
+            String::from("    return"),
+            String::from(".data"),
+            String::from("msg: \"abc\""),
+            String::from("msg_end:"),
+            String::from("table: lo(main) hi(msg_end) (msg_end - msg) msg_end-msg"),
+            String::from(".code"),
+            String::from("other:"),
+            String::from("    return"),
+        ]).unwrap();
+
+        assert_eq!(bin, vec![
+            Opcode::Push as u8, 0x03,
+            Opcode::PushAddr as u8, 0x11, 0x00,
+            Opcode::Push as u8, 0x10,
+            Opcode::Push as u8, 0x00,
+            Opcode::Push as u8, 0x03,
+            Opcode::Jump as u8, 0x00, 0x00,
+            Opcode::Return as u8,
+            Opcode::Return as u8,
+            b'a', b'b', b'c',
+            0x00, 0x00, 0x03, 0x03,
+        ]);
+    }
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, "push_addr label + 2", 1).unwrap();
+        parse_asm_line(&mut context, "push 0x10 + 0x20", 2).unwrap();
+
+        let code = context.get_code().get_code();
+        assert_eq!(code[0].get_literal(), &Literal::Expression(Expression::parse("label + 2").unwrap()));
+        assert_eq!(code[1].get_literal(), &Literal::Const(0x30));
+
+        assert!(parse_asm_line(&mut context, "push 0x10 + 0x100", 3).is_err());
+        assert!(parse_asm_line(&mut context, "push label", 4).is_err());
+        assert!(parse_asm_line(&mut context, "glob: label", 5).is_err());
+        assert!(parse_asm_line(&mut context, "glob: (1 +", 6).is_err());
+        assert!(parse_asm_line(&mut context, ".equ WITH_LABEL label + 1", 7).is_err());
+        assert!(parse_asm_line(&mut context, "push -(-9223372036854775807 - 1)", 7).is_err());
+
+        parse_asm_line(&mut context, ".data", 8).unwrap();
+        assert!(parse_asm_line(&mut context, "push 0x01", 9).is_err());
+    }
+    {
+        // Values of expressions that depend on labels are checked when writing the binary
+        let err = compile_from_asm(vec![
+            String::from("main:"),
+            String::from("    push lo(main) + 0x100"),
+        ]).unwrap_err();
+        assert_eq!(err, "2: 256 doesn't fit into a byte");
+
+        let err = compile_from_asm(vec![
+            String::from("main:"),
+            String::from("    push_addr missing + 1"),
+        ]).unwrap_err();
+        assert_eq!(err, "2: Unknown label 'missing'");
+
+        // Globals are placed after the code, so a label following a glob in the code section
+        // binds to the next instruction before the glob and the size is negative
+        let err = compile_from_asm(vec![
+            String::from("main:"),
+            String::from("    push msg_end - msg"),
+            String::from("msg: \"Hello world!\""),
+            String::from("msg_end:"),
+            String::from("    return"),
+        ]).unwrap_err();
+        assert_eq!(err, "2: label arithmetic result -1 is negative");
+    }
+}
+
+#[test]
+fn diagnostic_tests() {
+    {
+        // All errors are reported, not only the first one
+        let source = SourceLine::from_lines(&[
+            String::from("main:"),
+            String::from("    push 0x100"),
+            String::from("    jumpp main"),
+            String::from("    push label"),
+        ], "main.srd");
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 3);
+
+        assert_eq!(diagnostics[0].summary(), "main.srd:2:10: failed to parse value '0x100' - 256 doesn't fit into a byte");
+        assert_eq!(diagnostics[1].summary(), "main.srd:3:5: Invalid keyword");
+        assert_eq!(diagnostics[2].summary(), "main.srd:4:10: failed to parse value 'label' - \
+            'label' is not a constant, label addresses can only be used with lo() or hi()");
+
+        assert_eq!(diagnostics[1].to_string(), [
+            "error: Invalid keyword",
+            " --> main.srd:3:5",
+            "  |",
+            "3 |     jumpp main",
+            "  |     ^^^^^",
+        ].join("\n"));
+    }
+    {
+        // Errors found when writing the binary point at the operand
+        let source = SourceLine::from_lines(&[
+            String::from("main:"),
+            String::from("\tpush_addr missing + 1 ; comment"),
+            String::from("\tcall other"),
+            String::from("data: lo(main) hi(nowhere)"),
+        ], "main.srd");
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 3);
+
+        assert_eq!(diagnostics[0].to_string(), [
+            "error: Unknown label 'missing'",
+            " --> main.srd:2:12",
+            "  |",
+            "2 | \tpush_addr missing + 1 ; comment",
+            "  | \t          ^^^^^^^^^^^",
+        ].join("\n"));
+        assert_eq!(diagnostics[1].summary(), "main.srd:3:7: Unknown label 'other'");
+        assert_eq!(diagnostics[2].summary(), "main.srd:4:16: Unknown label 'nowhere'");
+    }
+    {
+        // Redefinitions point at the earlier definition
+        let source = vec![
+            SourceLine::new(".equ SIZE 2", "main.srd", 1),
+            SourceLine::new(".equ SIZE 3", "main.srd", 2),
+        ];
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].summary(), "main.srd:2:6: constant 'SIZE' is already defined at main.srd:1");
+        assert_eq!(diagnostics[0].span.as_ref().unwrap().location, Location::new("main.srd", 2));
+    }
+    {
+        // Notes of the line are kept
+        let mut second = SourceLine::new("label: 0x02", "module.srd", 7);
+        second.notes.push(String::from("in expansion of macro 'm'"));
+        let source = vec![
+            SourceLine::new("label: 0x01", "main.srd", 1),
+            second,
+        ];
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].summary(), "module.srd:7:1: global 'label' is already defined at main.srd:1");
+        assert_eq!(diagnostics[0].notes, vec![String::from("in expansion of macro 'm'")]);
+        assert!(diagnostics[0].to_string().ends_with("  = note: in expansion of macro 'm'"));
+    }
+}
+
+#[test]
+fn operand_tests() {
+    {
+        let mut context = Context::new();
+
+        let err = parse_asm_line(&mut context, "push", 1).unwrap_err();
+        assert_eq!(err, "1: 'push' is missing a byte operand");
+        let err = parse_asm_line(&mut context, "call", 2).unwrap_err();
+        assert_eq!(err, "2: 'call' is missing an address operand");
+        let err = parse_asm_line(&mut context, "add 0x01", 3).unwrap_err();
+        assert_eq!(err, "3: 'add' doesn't take an operand");
+        let err = parse_asm_line(&mut context, "push 0x01 0x02", 4).unwrap_err();
+        assert_eq!(err, "4: unexpected operand '0x02', 'push' takes a single operand");
+        let err = parse_asm_line(&mut context, "jump main + 1 other", 5).unwrap_err();
+        assert_eq!(err, "5: unexpected operand 'other', 'jump' takes a single operand");
+        let err = parse_asm_line(&mut context, "push_addr \"a\" \"b\"", 6).unwrap_err();
+        assert_eq!(err, "6: unexpected operand '\"b\"', 'push_addr' takes a single operand");
+        let err = parse_asm_line(&mut context, "push \"a\"", 7).unwrap_err();
+        assert_eq!(err, "7: 'push' expects a byte operand, strings can only be used as addresses");
+        let err = parse_asm_line(&mut context, "label main", 8).unwrap_err();
+        assert_eq!(err, "8: 'label' can't be used as an instruction, labels are written as 'name:'");
+
+        // Malformed expressions are reported as such, not as extra operands
+        let err = parse_asm_line(&mut context, "push 0x01 +", 9).unwrap_err();
+        assert!(err.starts_with("9: failed to parse value '0x01 +'"));
+
+        assert!(context.get_code().get_code().is_empty());
+    }
+    {
+        let source = SourceLine::from_lines(&[
+            String::from("main:"),
+            String::from("    stack_get"),
+            String::from("    return 0x00 ; comment"),
+        ], "main.srd");
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].summary(), "main.srd:2:5: 'stack_get' is missing a byte operand");
+        assert_eq!(diagnostics[1].summary(), "main.srd:3:12: 'return' doesn't take an operand");
+    }
+}
+
+#[test]
+fn symbol_tests() {
+    {
+        let mut context = Context::new();
+
+        parse_asm_line(&mut context, ".equ SIZE 0x10", 1).unwrap();
+        parse_asm_line(&mut context, "main:", 2).unwrap();
+        parse_asm_line(&mut context, "message: \"hi\"", 3).unwrap();
+
+        assert_eq!(context.get_symbol("SIZE").unwrap().kind, SymbolKind::Constant);
+        assert_eq!(context.get_symbol("main").unwrap().kind, SymbolKind::Label);
+        assert_eq!(context.get_symbol("message").unwrap().kind, SymbolKind::Glob);
+        assert_eq!(context.get_symbol("message").unwrap().location, Location::new("", 3));
+        assert!(context.get_symbol("missing").is_none());
+
+        let err = parse_asm_line(&mut context, "main:", 4).unwrap_err();
+        assert_eq!(err, "4: label 'main' is already defined at line 2");
+        let err = parse_asm_line(&mut context, "main: 0x01", 5).unwrap_err();
+        assert_eq!(err, "5: label 'main' is already defined at line 2");
+        let err = parse_asm_line(&mut context, "message:", 6).unwrap_err();
+        assert_eq!(err, "6: global 'message' is already defined at line 3");
+        let err = parse_asm_line(&mut context, "SIZE:", 7).unwrap_err();
+        assert_eq!(err, "7: constant 'SIZE' is already defined at line 1");
+        let err = parse_asm_line(&mut context, ".equ main 0x02", 8).unwrap_err();
+        assert_eq!(err, "8: label 'main' is already defined at line 2");
+
+        parse_asm_line(&mut context, ".data", 9).unwrap();
+        let err = parse_asm_line(&mut context, "main:", 10).unwrap_err();
+        assert_eq!(err, "10: label 'main' is already defined at line 2");
+    }
+    {
+        // Duplicates are found before writing the binary and point at both definitions
+        let source = vec![
+            SourceLine::new("main:", "main.srd", 1),
+            SourceLine::new("    return", "main.srd", 2),
+            SourceLine::new("data: 0x01", "main.srd", 3),
+            SourceLine::new("  main:", "module.srd", 4),
+            SourceLine::new("data: 0x02", "module.srd", 5),
+        ];
+        let diagnostics = compile(&source).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].summary(), "module.srd:4:3: label 'main' is already defined at main.srd:1");
+        assert_eq!(diagnostics[1].summary(), "module.srd:5:1: global 'data' is already defined at main.srd:3");
+    }
+}
+
+#[test]
+fn disassembler_tests() {
+    let source = SourceLine::from_lines(&[
+        String::from("main:"),
+        String::from("    push_addr message"),
+        String::from("    call print"),
+        String::from("    eqz main"),
+        String::from("print:"),
+        String::from("    push 0x01"),
+        String::from("    return"),
+        String::from("message: \"Hi\\n\" 0x00"),
+    ], "");
+    let (bin, symbols) = compile_with_symbols(&source).unwrap();
+    assert_eq!(symbols.code_size, 12);
+    assert_eq!(symbols.get_address("print"), Some(9));
+    assert_eq!(symbols.name_at(12), Some("message"));
+    assert_eq!(symbols.symbols_at(12).next().unwrap().kind, SymbolKind::Glob);
+    {
+        // Address operand at the end of the code points to the global, not to a label there
+        let mut map = SymbolMap::new(12);
+        map.add_symbol("code_end", 12, SymbolKind::Label);
+        map.add_symbol("message", 12, SymbolKind::Glob);
+        map.add_symbol("main", 0, SymbolKind::Label);
+        map.add_symbol("main", 9, SymbolKind::Label);
+        map.add_symbol("past_end", 20, SymbolKind::Glob);
+        assert_eq!(map.name_at(12), Some("message"));
+
+        let mut names = AddressNames::new(Some(&map), bin.len());
+        names.add_label(0);
+        names.add_label(3);
+        assert_eq!(names.name_at(12), Some("message"));
+        assert_eq!(names.names_at(12).len(), 2);
+        assert_eq!(names.name_at(0), Some("main"));
+        assert_eq!(names.name_at(3), Some("label_0003"));
+        // Name used twice is kept at its first address, symbols past the image are dropped
+        assert_eq!(names.name_at(9), None);
+        assert_eq!(names.iter().map(|(address, _)| address).collect::<Vec<u16>>(), vec![0, 3, 12]);
+    }
+
+    {
+        let map = SymbolMap::from_text(&symbols.to_text()).unwrap();
+        assert_eq!(map, symbols);
+        assert_eq!(SymbolMap::from_text("label 0x10000 main").unwrap_err(), "1: invalid address '0x10000' - 65536 doesn't fit into 16 bits");
+        assert_eq!(SymbolMap::from_text("\nfunction 0x00 main").unwrap_err(), "2: invalid symbol entry 'function 0x00 main'");
+    }
+    {
+        assert_eq!(disassemble(&bin, Some(&symbols)).unwrap(), [
+            "main:",
+            "    0000  05 0c 00  push_addr message       ; line 2",
+            "    0003  01 09 00  call print              ; line 3",
+            "    0006  40 00 00  eqz main                ; line 4",
+            "print:",
+            "    0009  04 01     push 0x01               ; line 6",
+            "    000b  00        return                  ; line 7",
+            "message:",
+            "    000c  48 69 0a 00              ; \"Hi..\"",
+            "",
+        ].join("\n"));
+
+        // Without symbols branch targets get generated labels and everything is decoded as code
+        assert_eq!(disassemble(&bin[..12], None).unwrap(), [
+            "label_0000:",
+            "    0000  05 0c 00  push_addr 0x000c",
+            "    0003  01 09 00  call label_0009",
+            "    0006  40 00 00  eqz label_0000",
+            "label_0009:",
+            "    0009  04 01     push 0x01",
+            "    000b  00        return",
+            "",
+        ].join("\n"));
+        assert_eq!(disassemble(&[0xff, Opcode::Label as u8, Opcode::Call as u8, 0x00], None).unwrap(), [
+            "    0000  ff        ; unknown opcode 0xff",
+            "    0001  07        ; unknown opcode 0x07",
+            "    0002  01        ; unknown opcode 0x01",
+            "    0003  00        return",
+            "",
+        ].join("\n"));
+
+        // Addresses past 0xffff can't be disassembled
+        assert!(disassemble(&vec![0x00; 0x10000], None).is_ok());
+        assert_eq!(disassemble(&vec![0x00; 0x10002], None).unwrap_err(), "Image of 65538 bytes doesn't fit into 65536 bytes of address space");
+    }
+    {
+        let instruction = decode_instruction(&bin, 3).unwrap();
+        assert_eq!(instruction, DecodedInstruction { address: 3, opcode: Opcode::Call, operand: Some(9) });
+        assert_eq!(instruction.size(), 3);
+        assert_eq!(format_instruction(&instruction, &AddressNames::new(Some(&symbols), bin.len())), "call print");
+        assert_eq!(format_instruction(&instruction, &AddressNames::default()), "call 0x0009");
+        assert!(decode_instruction(&bin, 100).is_none());
+        assert!(decode_instruction(&bin[..4], 3).is_none());
+    }
+}
+
+#[test]
+fn object_tests() {
+    let main_source = SourceLine::from_lines(&[
+        String::from(".equ OFFSET 2"),
+        String::from("main:"),
+        String::from("    push_addr \"main\""),
+        String::from("    push lo(table + OFFSET)"),
+        String::from("    call print"),
+        String::from("    return"),
+        String::from("table: 0x01 hi(print)"),
+    ], "main.srd");
+    let print_source = SourceLine::from_lines(&[
+        String::from("print:"),
+        String::from("    push_addr \"print\""),
+        String::from("    jump print"),
+    ], "print.srd");
+
+    let main_object = compile_object(&main_source).unwrap();
+    let print_object = compile_object(&print_source).unwrap();
+    {
+        assert_eq!(main_object.code.len(), 9);
+        assert_eq!(main_object.data, vec![b'm', b'a', b'i', b'n', 0x01, 0x00]);
+        assert_eq!(main_object.get_symbol("table").unwrap().section, Section::Data);
+        assert_eq!(main_object.get_symbol("table").unwrap().offset, 4);
+        assert!(main_object.get_symbol(".string_0").unwrap().is_local());
+        assert!(!main_object.get_symbol("main").unwrap().is_local());
+
+        // References to other modules are left to the linker, constants are substituted
+        assert_eq!(main_object.relocations, vec![
+            Relocation { section: Section::Code, offset: 1, width: 2, expression: Expression::parse(".string_0").unwrap() },
+            Relocation { section: Section::Code, offset: 4, width: 1, expression: Expression::parse("lo(table + 2)").unwrap() },
+            Relocation { section: Section::Code, offset: 6, width: 2, expression: Expression::parse("print").unwrap() },
+            Relocation { section: Section::Data, offset: 5, width: 1, expression: Expression::parse("hi(print)").unwrap() },
+        ]);
+
+        let bytes = main_object.to_bytes();
+        assert_eq!(&bytes[..4], b"SHOB");
+        assert_eq!(ObjectFile::from_bytes(&bytes).unwrap(), main_object);
+        assert_eq!(ObjectFile::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), "object file is truncated");
+        assert_eq!(ObjectFile::from_bytes(b"SHRD\x01").unwrap_err(), "not a shard object file");
+    }
+    {
+        // Linking separately compiled modules gives the same image as compiling them together
+        let objects = vec![(String::from("main.sho"), main_object.clone()), (String::from("print.sho"), print_object.clone())];
+        let (bin, symbols) = link(&objects).unwrap();
+
+        let mut source = main_source.clone();
+        source.extend(print_source.iter().cloned());
+        let (expected_bin, expected_symbols) = compile_with_symbols(&source).unwrap();
+        assert_eq!(bin, expected_bin);
+        assert_eq!(symbols.code_size, expected_symbols.code_size);
+        assert_eq!(symbols.get_address("print"), expected_symbols.get_address("print"));
+
+        // Both objects have their own .string_0
+        assert_eq!(symbols.symbols.iter().filter(|symbol| symbol.name == ".string_0").count(), 2);
+    }
+    {
+        let objects = vec![(String::from("main.sho"), main_object.clone())];
+        let diagnostics = link(&objects).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].message, "undefined symbol 'print' referenced in main.sho");
+
+        let objects = vec![(String::from("a.sho"), print_object.clone()), (String::from("b.sho"), print_object.clone())];
+        let diagnostics = link(&objects).unwrap_err();
+        assert_eq!(diagnostics[0].message, "symbol 'print' is defined in both a.sho and b.sho");
+    }
+}
+
+#[test]
+fn load_binary_tests() {
+    let source = SourceLine::from_lines(&[
+        String::from("main:"),
+        String::from("    push_addr message"),
+        String::from("    call print"),
+        String::from("    eqz main"),
+        String::from("print:"),
+        String::from("    push 0x01"),
+        String::from("    return"),
+        String::from("end:"),
+        String::from("message: \"Hi\\n\" 0x00"),
+        String::from("table: 0x01 0x02"),
+    ], "");
+    let (bin, symbols) = compile_with_symbols(&source).unwrap();
+    {
+        let mut context = Context::new();
+        context.load_binary(&bin, Some(&symbols)).unwrap();
+
+        let code = context.get_code().get_code();
+        assert_eq!(code.len(), 8);
+        assert_eq!(code[0].get_opcode(), Opcode::Label);
+        assert_eq!(code[0].get_literal(), &Literal::Label(String::from("main")));
+        assert_eq!(code[1].get_literal(), &Literal::Label(String::from("message")));
+        assert_eq!(code[5].get_literal(), &Literal::Const(0x01));
+        assert_eq!(code[7].get_literal(), &Literal::Label(String::from("end")));
+
+        let globs = context.get_globs();
+        assert_eq!(globs.iter().map(|glob| glob.get_name().as_str()).collect::<Vec<_>>(), vec!["message", "table"]);
+        assert_eq!(globs[0].get_value(), &vec![b'H', b'i', b'\n', 0x00]);
+
+        assert_eq!(context.build_binary_with_symbols().unwrap(), (bin.clone(), symbols.clone()));
+        assert_eq!(context.load_binary(&bin, Some(&symbols)).unwrap_err(), "binary can only be loaded into an empty context");
+    }
+    {
+        // Without symbols everything is code, branch targets get generated labels
+        let mut context = Context::new();
+        context.load_binary(&bin[..12], None).unwrap();
+        let code = context.get_code().get_code();
+        assert_eq!(code[0].get_literal(), &Literal::Label(String::from("label_0000")));
+        assert_eq!(code[1].get_literal(), &Literal::Address(0x000c));
+        assert_eq!(context.get_symbol("label_0009").unwrap().kind, SymbolKind::Label);
+        assert_eq!(context.build_binary().unwrap(), bin[..12].to_vec());
+    }
+    {
+        let image = [Opcode::Return as u8, 0xff];
+        assert_eq!(Context::new().load_binary(&image, None).unwrap_err(), "invalid instruction at 0x0001");
+
+        let mut map = symbols.clone();
+        map.add_symbol("inside", 4, SymbolKind::Label);
+        assert_eq!(Context::new().load_binary(&bin, Some(&map)).unwrap_err(), "'inside' at 0x0004 points inside an instruction");
+    }
+}
+
+#[test]
+fn entry_tests() {
+    let source = SourceLine::from_lines(&[
+        String::from("helper:"),
+        String::from("    push 0x01"),
+        String::from("    return"),
+        String::from(".entry main"),
+        String::from("main:"),
+        String::from("    call helper"),
+        String::from("    return"),
+        String::from("message: 0x01"),
+    ], "main.srd");
+    {
+        let (bin, symbols) = compile_with_symbols(&source).unwrap();
+        assert_eq!(symbols.entry, Some(3));
+        assert!(symbols.to_text().starts_with("code_size 0x0007\nentry 0x0003\n"));
+        assert_eq!(SymbolMap::from_text(&symbols.to_text()).unwrap(), symbols);
+
+        // Raw image jumps to the entry point first
+        let (stub_bin, stub_symbols) = compile_with_entry_stub(&source).unwrap();
+        assert_eq!(&stub_bin[..3], &[Opcode::Jump as u8, 0x06, 0x00]);
+        assert_eq!(&stub_bin[3..6], &bin[..3]);
+        assert_eq!(stub_symbols.entry, Some(6));
+
+        let mut context = Context::new();
+        context.load_binary(&bin, Some(&symbols)).unwrap();
+        assert_eq!(context.get_entry(), Some("main"));
+        assert_eq!(context.build_binary_with_symbols().unwrap(), (bin, symbols));
+    }
+    {
+        let mut lines = source.clone();
+        lines.push(SourceLine::new(".entry helper", "<command line>", 1));
+        let diagnostics = compile_with_symbols(&lines).unwrap_err();
+        assert_eq!(diagnostics[0].summary(), "<command line>:1:8: entry point is already set to 'main' at main.srd:4");
+
+        let diagnostics = compile_with_symbols(&SourceLine::from_lines(&[String::from(".entry start"), String::from("return")], "")).unwrap_err();
+        assert_eq!(diagnostics[0].summary(), "1: Unknown entry point 'start'");
+        let diagnostics = compile_with_symbols(&SourceLine::from_lines(&[String::from(".entry message"), String::from("message: 0x01")], "")).unwrap_err();
+        assert_eq!(diagnostics[0].summary(), "1: entry point 'message' is not in the code");
+        assert_eq!(compile_from_asm(vec![String::from(".entry")]).unwrap_err(), "1: '.entry' is missing a label");
+        assert_eq!(compile_from_asm(vec![String::from(".entry a b")]).unwrap_err(), "1: unexpected operand 'b', '.entry' takes a single label");
+    }
+    {
+        let main_object = compile_object(&source[3..]).unwrap();
+        assert_eq!(main_object.entry, Some(String::from("main")));
+        assert_eq!(ObjectFile::from_bytes(&main_object.to_bytes()).unwrap(), main_object);
+        let helper_object = compile_object(&source[..3]).unwrap();
+
+        let objects = vec![(String::from("helper.sho"), helper_object), (String::from("main.sho"), main_object.clone())];
+        let (_, symbols) = link(&objects).unwrap();
+        assert_eq!(symbols.entry, Some(3));
+
+        let objects = vec![(String::from("a.sho"), main_object.clone()), (String::from("b.sho"), main_object)];
+        let diagnostics = link(&objects).unwrap_err();
+        assert!(diagnostics.iter().any(|diagnostic| diagnostic.message == "entry point is set in both a.sho and b.sho"));
+    }
+}
+
+#[test]
+fn line_mapping_tests() {
+    let source = SourceLine::from_lines(&[
+        String::from("main:"),
+        String::from("    push 0x01"),
+        String::from(""),
+        String::from("    call print"),
+        String::from("    return"),
+        String::from("print:"),
+        String::from("    pop"),
+        String::from("    return"),
+        String::from("message: 0x01"),
+    ], "main.srd");
+    let (_, symbols) = compile_with_symbols(&source).unwrap();
+    {
+        assert_eq!(symbols.lines.iter().map(|mapping| (mapping.address, mapping.line)).collect::<Vec<_>>(), vec![
+            (0, 2), (2, 4), (5, 5), (6, 7), (7, 8),
+        ]);
+        assert_eq!(symbols.line_at(3).unwrap().to_string(), "main.srd:4");
+        assert!(symbols.line_at(8).is_none());
+        assert_eq!(symbols.label_before(7).unwrap().name, "print");
+        assert_eq!(symbols.label_before(5).unwrap().name, "main");
+
+        let text = symbols.to_text();
+        assert!(text.contains("line 0x0002 main.srd:4\n"));
+        assert_eq!(SymbolMap::from_text(&text).unwrap(), symbols);
+        assert_eq!(SymbolMap::from_text("line 0x0002 my file.srd:4").unwrap().lines[0].file, "my file.srd");
+        assert_eq!(SymbolMap::from_text("line 0x0002 main.srd").unwrap_err(), "1: invalid source line 'main.srd'");
+    }
+    {
+        // Objects keep the lines of their code, the linker moves them with it
+        let main_object = compile_object(&source[..5]).unwrap();
+        let print_object = compile_object(&source[5..]).unwrap();
+        assert_eq!(ObjectFile::from_bytes(&print_object.to_bytes()).unwrap(), print_object);
+
+        let objects = vec![(String::from("main.sho"), main_object), (String::from("print.sho"), print_object)];
+        let (_, linked_symbols) = link(&objects).unwrap();
+        assert_eq!(linked_symbols.lines, symbols.lines);
+    }
+}
@@ -0,0 +1,146 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+
+use std::convert::TryInto;
+
+pub const IMAGE_MAGIC: [u8; 4] = *b"SHRD";
+pub const IMAGE_FORMAT_VERSION: u16 = 1;
+// Bumped whenever opcodes or their encoding change
+pub const ISA_VERSION: u16 = 1;
+pub const IMAGE_HEADER_SIZE: usize = 20;
+
+// Header in front of the code and data of an executable image. Serialized format (all values little-endian):
+//   magic "SHRD", format_version u16, isa_version u16,
+//   entry u16, code_size u16, data_size u16, required_ram u16,
+//   checksum u32 (CRC-32 of the code and data)
+// Images without the header are raw images, code starts at the first byte and the entry point is 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub format_version: u16,
+    pub isa_version: u16,
+    pub entry: u16,
+    pub code_size: u16,
+    pub data_size: u16,
+    // Bytes of ram the program needs on top of the stacks
+    pub required_ram: u16,
+    pub checksum: u32,
+}
+
+impl ImageHeader {
+    // Header of the current version for code followed by data
+    pub fn new(payload: &[u8], code_size: u16, entry: u16, required_ram: u16) -> Result<ImageHeader, String> {
+        let data_size = match payload.len().checked_sub(code_size as usize) {
+            Some(data_size) => data_size,
+            None => return Err(format!("code size {} is larger than the {} bytes of code and data", code_size, payload.len())),
+        };
+        let data_size: u16 = data_size.try_into().map_err(|_| format!("{} bytes of data don't fit into the header", data_size))?;
+
+        Ok(ImageHeader {
+            format_version: IMAGE_FORMAT_VERSION,
+            isa_version: ISA_VERSION,
+            entry,
+            code_size,
+            data_size,
+            required_ram,
+            checksum: checksum(payload),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(IMAGE_HEADER_SIZE);
+        bytes.extend_from_slice(&IMAGE_MAGIC);
+        for value in [self.format_version, self.isa_version, self.entry, self.code_size, self.data_size, self.required_ram].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    // Reads the header without checking it against the rest of the image
+    pub fn from_bytes(bytes: &[u8]) -> Result<ImageHeader, String> {
+        if !has_header(bytes) {
+            return Err(String::from("not a shard image"));
+        }
+        if bytes.len() < IMAGE_HEADER_SIZE {
+            return Err(String::from("image header is truncated"));
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        Ok(ImageHeader {
+            format_version: u16_at(4),
+            isa_version: u16_at(6),
+            entry: u16_at(8),
+            code_size: u16_at(10),
+            data_size: u16_at(12),
+            required_ram: u16_at(14),
+            checksum: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        })
+    }
+}
+
+pub fn has_header(image: &[u8]) -> bool {
+    image.starts_with(&IMAGE_MAGIC)
+}
+
+// Header followed by code and data
+pub fn write_image(payload: &[u8], code_size: u16, entry: u16, required_ram: u16) -> Result<Vec<u8>, String> {
+    let mut image = ImageHeader::new(payload, code_size, entry, required_ram)?.to_bytes();
+    image.extend_from_slice(payload);
+    Ok(image)
+}
+
+// Validates the header and returns it together with the code and data
+pub fn read_image(image: &[u8]) -> Result<(ImageHeader, &[u8]), String> {
+    let header = ImageHeader::from_bytes(image)?;
+    if header.format_version != IMAGE_FORMAT_VERSION {
+        return Err(format!("unsupported image format version {}", header.format_version));
+    }
+    if header.isa_version != ISA_VERSION {
+        return Err(format!("image is built for ISA version {}, expected {}", header.isa_version, ISA_VERSION));
+    }
+
+    let payload = &image[IMAGE_HEADER_SIZE..];
+    if payload.len() != header.code_size as usize + header.data_size as usize {
+        return Err(format!("image has {} bytes of code and data, header expects {}", payload.len(), header.code_size as usize + header.data_size as usize));
+    }
+    if header.entry != 0 && header.entry >= header.code_size {
+        return Err(format!("entry point 0x{:04x} is outside of the code", header.entry));
+    }
+    let actual_checksum = checksum(payload);
+    if actual_checksum != header.checksum {
+        return Err(format!("checksum 0x{:08x} doesn't match 0x{:08x} in the header, image is corrupted", actual_checksum, header.checksum));
+    }
+    Ok((header, payload))
+}
+
+// CRC-32 (IEEE)
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
@@ -17,4 +17,5 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+pub mod image;
 pub mod opcodes;
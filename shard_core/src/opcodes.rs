@@ -76,9 +76,38 @@ pub enum Opcode {
     Rotr = 0x70,
 }
 
+// Operand that follows the opcode in the binary
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OperandKind {
+    None,
+    // Byte constant
+    U8,
+    // Address, label or 16-bit constant
+    U16,
+}
+
+impl OperandKind {
+    // Size of the operand in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            OperandKind::None => 0,
+            OperandKind::U8 => 1,
+            OperandKind::U16 => 2,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            OperandKind::None => "no operand",
+            OperandKind::U8 => "a byte operand",
+            OperandKind::U16 => "an address operand",
+        }
+    }
+}
+
 impl Opcode {
     pub fn from_string(value: &str) -> Option<Opcode> {
-        return match value {
+        match value {
             "return" => Some(Opcode::Return),
             "call" => Some(Opcode::Call),
             "jump" => Some(Opcode::Jump),
@@ -136,7 +165,7 @@ impl Opcode {
     }
 
     pub fn to_string(&self) ->  &'static str {
-        return match self {
+        match self {
             Opcode::Return => "return",
             Opcode::Call => "call",
             Opcode::Jump => "jump",
@@ -192,40 +221,59 @@ impl Opcode {
         }
     }
 
+    // Operand schema of every opcode
+    pub fn operand_kind(&self) -> OperandKind {
+        match self {
+            Opcode::Push | Opcode::StackGet | Opcode::StackSet => OperandKind::U8,
+            Opcode::Call
+            | Opcode::Jump
+            | Opcode::PushAddr
+            | Opcode::Load8
+            | Opcode::Load16
+            | Opcode::Store8
+            | Opcode::Store16
+            | Opcode::Eqz
+            | Opcode::Eq
+            | Opcode::Ne
+            | Opcode::LtS
+            | Opcode::LtU
+            | Opcode::GtS
+            | Opcode::GtU
+            | Opcode::LeS
+            | Opcode::LeU
+            | Opcode::GeS
+            | Opcode::GeU => OperandKind::U16,
+            // Label is not encoded, its name comes from the source
+            _ => OperandKind::None,
+        }
+    }
+
+    // Opcodes that may continue at their address operand
+    pub fn is_branch(&self) -> bool {
+        matches!(self,
+            Opcode::Call
+            | Opcode::Jump
+            | Opcode::Eqz
+            | Opcode::Eq
+            | Opcode::Ne
+            | Opcode::LtS
+            | Opcode::LtU
+            | Opcode::GtS
+            | Opcode::GtU
+            | Opcode::LeS
+            | Opcode::LeU
+            | Opcode::GeS
+            | Opcode::GeU
+        )
+    }
+
+    // Pseudo opcodes are produced by the assembler and can't be written as instructions
+    pub fn is_pseudo(&self) -> bool {
+        matches!(self, Opcode::Label)
+    }
+
     // Opcodes that have no additional arguments and act on their own
     pub fn is_opcode_instruction(opcode: Opcode) -> bool {
-        return match opcode {
-            Opcode::Nop => true,
-            Opcode::Return => true,
-            Opcode::Sys => true,
-            Opcode::JumpC => true,
-            Opcode::Pop => true,
-            Opcode::GetRegA => true,
-            Opcode::GetRegB => true,
-            Opcode::SetRegA => true,
-            Opcode::SetRegB => true,
-            Opcode::Load8C => true,
-            Opcode::Load16C => true,
-            Opcode::Store8C => true,
-            Opcode::Store16C => true,
-            Opcode::Add => true,
-            Opcode::Sub => true,
-            Opcode::Mul => true,
-            Opcode::DivS => true,
-            Opcode::DivU => true,
-            Opcode::RemS => true,
-            Opcode::RemU => true,
-            Opcode::Pow => true,
-            Opcode::Abs => true,
-            Opcode::And => true,
-            Opcode::Or => true,
-            Opcode::Xor => true,
-            Opcode::Shl => true,
-            Opcode::ShrS => true,
-            Opcode::ShrU => true,
-            Opcode::Rotl => true,
-            Opcode::Rotr => true,
-            _ => false
-        }
+        opcode.operand_kind() == OperandKind::None && !opcode.is_pseudo()
     }
 }
@@ -0,0 +1,107 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::{error::Error, fmt};
+use shard_core::opcodes::Opcode;
+use crate::memory::{MemoryAccess, MemoryRegion};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    StackOverflow,
+    StackUnderflow,
+    StackOffsetOutOfRange { offset: u8 },
+    CallStackOverflow,
+    CallStackUnderflow,
+    UnknownOpcode { byte: u8, pc: u16 },
+    UnimplementedOpcode(Opcode),
+    MemoryFault { address: u16 },
+    // Access denied by memory protection
+    ProtectionFault { address: u16, region: MemoryRegion, access: MemoryAccess },
+    DivisionByZero,
+    ImageTooLarge { size: usize, limit: usize },
+    InvalidSnapshot(String),
+    // Image header is malformed or doesn't fit this VM
+    InvalidImage(String),
+    // Error reported by the host's interrupt handler
+    Interrupt(String),
+    // Error raised while executing an instruction. Wraps the underlying error
+    // together with the address and opcode of the faulting instruction.
+    Fault { pc: u16, opcode: Opcode, error: Box<VmError> },
+}
+
+impl VmError {
+    // Underlying error with any instruction fault context stripped
+    pub fn root(&self) -> &VmError {
+        match self {
+            VmError::Fault { error, .. } => error.root(),
+            _ => self,
+        }
+    }
+
+    pub fn pc(&self) -> Option<u16> {
+        match self {
+            VmError::Fault { pc, .. } => Some(*pc),
+            VmError::UnknownOpcode { pc, .. } => Some(*pc),
+            _ => None,
+        }
+    }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self {
+            VmError::Fault { opcode, .. } => Some(*opcode),
+            VmError::UnimplementedOpcode(opcode) => Some(*opcode),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackOverflow => write!(f, "Stack overflow"),
+            VmError::StackUnderflow => write!(f, "Stack is empty"),
+            VmError::StackOffsetOutOfRange { offset } => write!(f, "Stack offset {:#04x} out of range", offset),
+            VmError::CallStackOverflow => write!(f, "Call stack overflow"),
+            VmError::CallStackUnderflow => write!(f, "Call stack is empty"),
+            VmError::UnknownOpcode { byte, pc } => write!(f, "Unknown opcode byte {:#04x} at {:#06x}", byte, pc),
+            VmError::UnimplementedOpcode(opcode) => write!(f, "Opcode '{}' has no implementation", opcode.to_string()),
+            VmError::MemoryFault { address } => write!(f, "Memory fault at {:#06x}", address),
+            VmError::ProtectionFault { address, region, access } => {
+                write!(f, "Memory fault: {} access to {} region at {:#06x}", access, region, address)
+            }
+            VmError::DivisionByZero => write!(f, "Division by zero"),
+            VmError::ImageTooLarge { size, limit } => write!(f, "Code size {} exceeding {} limit", size, limit),
+            VmError::InvalidSnapshot(message) => write!(f, "Invalid snapshot: {}", message),
+            VmError::InvalidImage(message) => write!(f, "Invalid image: {}", message),
+            VmError::Interrupt(message) => write!(f, "{}", message),
+            VmError::Fault { pc, opcode, error } => {
+                write!(f, "{} (at {:#06x} '{}')", error, pc, opcode.to_string())
+            }
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Fault { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
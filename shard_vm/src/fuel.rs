@@ -0,0 +1,48 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use shard_core::opcodes::Opcode;
+
+pub const DEFAULT_INSTRUCTION_COST: u32 = 1;
+
+// Amount of fuel every opcode consumes when fuel metering is enabled
+#[derive(Clone)]
+pub struct CostTable {
+    costs: [u32; 256],
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable { costs: [DEFAULT_INSTRUCTION_COST; 256] }
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u32) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    pub fn get_cost(&self, opcode: Opcode) -> u32 {
+        self.costs[opcode as usize]
+    }
+}
@@ -17,7 +17,10 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+pub mod error;
+pub mod fuel;
 pub mod memory;
+pub mod snapshot;
 pub mod vm;
 
 #[cfg(test)]
@@ -17,34 +17,136 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::Instant;
+use crate::error::VmError;
 use crate::vm::{VM_STACK_SIZE, VM_MAX_IMAGE_SIZE};
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryRegion {
+    Code,
+    Data,
+    Stack,
+    CallStack,
+    Ram,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryRegion::Code => write!(f, "code"),
+            MemoryRegion::Data => write!(f, "data"),
+            MemoryRegion::Stack => write!(f, "stack"),
+            MemoryRegion::CallStack => write!(f, "call stack"),
+            MemoryRegion::Ram => write!(f, "ram"),
+        }
+    }
+}
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryAccess::Read => write!(f, "read"),
+            MemoryAccess::Write => write!(f, "write"),
+            MemoryAccess::Execute => write!(f, "execute"),
+        }
+    }
+}
+
 pub trait Memory {
-    fn write_u8(&mut self, address: u16, value: u8) -> Result<(), String>;
-    fn read_u8(&self, address: u16) -> Result<u8, String>;
+    // Data accesses made by guest load/store instructions and by the host
+    fn write_u8(&mut self, address: u16, value: u8) -> Result<(), VmError>;
+    fn read_u8(&self, address: u16) -> Result<u8, VmError>;
+
+    // Opcode and operand fetches
+    fn fetch_u8(&self, address: u16) -> Result<u8, VmError> {
+        self.read_u8(address)
+    }
+
+    // Data stack and call stack accesses made by the VM itself
+    fn stack_write_u8(&mut self, address: u16, value: u8) -> Result<(), VmError> {
+        self.write_u8(address, value)
+    }
+
+    fn stack_read_u8(&self, address: u16) -> Result<u8, VmError> {
+        self.read_u8(address)
+    }
 
     fn stack_start_address(&self) -> u16;
     fn call_stack_start_address(&self) -> u16;
     fn ram_start_address(&self) -> u16;
 
+    // End of the instructions, the rest of the image up to the stack is data
+    fn code_size(&self) -> u16 {
+        self.stack_start_address()
+    }
+
+    fn is_protection_enabled(&self) -> bool {
+        false
+    }
+
     fn dump_memory(&self) -> Vec<u8>;
+    // Bytes from start up to and including end so the last byte of memory can be dumped,
+    // empty when end is before start
     fn dump_memory_range(&self, start: u16, end: u16) -> Vec<u8>;
+
+    // Overwrites memory with an image previously taken with dump_memory
+    fn restore_memory(&mut self, image: &[u8]) -> Result<(), VmError> {
+        for (address, value) in image.iter().enumerate() {
+            self.write_u8(address as u16, *value)?;
+        }
+        Ok(())
+    }
+}
+
+// Layout DefaultMemory::new creates: code and data, then both stacks of VM_STACK_SIZE bytes and ram
+// starting below the top of memory
+pub fn check_layout(code_size: u16, stack_start_address: u16, call_stack_start_address: u16, ram_start_address: u16) -> Result<(), VmError> {
+    let valid = code_size <= stack_start_address &&
+        call_stack_start_address as usize == stack_start_address as usize + VM_STACK_SIZE &&
+        ram_start_address as usize == call_stack_start_address as usize + VM_STACK_SIZE;
+    match valid {
+        true => Ok(()),
+        false => Err(VmError::InvalidSnapshot(String::from("Invalid memory layout"))),
+    }
 }
 
 pub struct DefaultMemory {
     memory: Vec<u8>,
+    // Instructions end here, globals follow up to the stack
+    code_size: u16,
     stack_start_address: u16,
     call_stack_start_address: u16,
     ram_start_address: u16,
+    // When enabled code is read/execute only, stacks are reachable only through stack
+    // operations and globals and ram are read/write only
+    protection: bool,
 }
 
 impl DefaultMemory {
-    pub fn new(code: Vec<u8>) -> Result<DefaultMemory, String> {
-        if code.len() > u16::MAX as usize {
-            return Err(String::from(format!(
-                    "Code size {} exceeding {} limit",
-                code.len(), u16::MAX
-            )));
+    // The whole image is treated as code, use new_with_code_size when globals follow the code
+    pub fn new(code: Vec<u8>) -> Result<DefaultMemory, VmError> {
+        let code_size = code.len();
+        DefaultMemory::new_with_code_size(code, code_size)
+    }
+
+    // Only the first code_size bytes of the image are code, the rest are globals
+    pub fn new_with_code_size(code: Vec<u8>, code_size: usize) -> Result<DefaultMemory, VmError> {
+        // Both stacks and the ram start address have to fit in the address space
+        if code.len() + VM_STACK_SIZE * 2 > u16::MAX as usize {
+            return Err(VmError::ImageTooLarge { size: code.len(), limit: u16::MAX as usize - VM_STACK_SIZE * 2 });
+        }
+        if code_size > code.len() {
+            return Err(VmError::InvalidImage(format!("Code size {} is larger than the image size {}", code_size, code.len())));
         }
 
         let mut memory = vec![];
@@ -52,34 +154,96 @@ impl DefaultMemory {
         memory.append(&mut code_temp);
 
         let stack_start_address = memory.len() as u16;
-        let mut stack = vec![0 as u8; VM_STACK_SIZE];
+        let mut stack = vec![0u8; VM_STACK_SIZE];
         memory.append(&mut stack);
 
         let call_stack_start_address = memory.len() as u16;
-        let mut call_stack = vec![0 as u8; VM_STACK_SIZE];
+        let mut call_stack = vec![0u8; VM_STACK_SIZE];
         memory.append(&mut call_stack);
 
         let ram_start_address = memory.len() as u16;
-        let mut ram = vec![0 as u8; VM_MAX_IMAGE_SIZE - memory.len()];
+        let mut ram = vec![0u8; VM_MAX_IMAGE_SIZE - memory.len()];
         memory.append(&mut ram);
 
         assert_eq!(memory.len(), VM_MAX_IMAGE_SIZE);
 
-        Ok(DefaultMemory { memory, stack_start_address, call_stack_start_address, ram_start_address })
+        Ok(DefaultMemory { memory, code_size: code_size as u16, stack_start_address, call_stack_start_address, ram_start_address, protection: false })
+    }
+
+    // Creates memory from a full memory image, e.g. one stored in a VmSnapshot
+    pub fn new_with_layout(memory: Vec<u8>, code_size: u16, stack_start_address: u16, call_stack_start_address: u16, ram_start_address: u16) -> Result<DefaultMemory, VmError> {
+        if memory.len() != VM_MAX_IMAGE_SIZE {
+            return Err(VmError::InvalidSnapshot(format!("Memory image size {} doesn't match {}", memory.len(), VM_MAX_IMAGE_SIZE)));
+        }
+        check_layout(code_size, stack_start_address, call_stack_start_address, ram_start_address)?;
+
+        Ok(DefaultMemory { memory, code_size, stack_start_address, call_stack_start_address, ram_start_address, protection: false })
+    }
+
+    pub fn set_protection(&mut self, enabled: bool) {
+        self.protection = enabled;
+    }
+
+    pub fn region(&self, address: u16) -> MemoryRegion {
+        if address < self.code_size {
+            MemoryRegion::Code
+        } else if address < self.stack_start_address {
+            MemoryRegion::Data
+        } else if address < self.call_stack_start_address {
+            MemoryRegion::Stack
+        } else if address < self.ram_start_address {
+            MemoryRegion::CallStack
+        } else {
+            MemoryRegion::Ram
+        }
+    }
+
+    fn check_access(&self, address: u16, access: MemoryAccess) -> Result<(), VmError> {
+        if !self.protection {
+            return Ok(());
+        }
+
+        let region = self.region(address);
+        let allowed = match region {
+            MemoryRegion::Code => access != MemoryAccess::Write,
+            MemoryRegion::Stack | MemoryRegion::CallStack => false,
+            MemoryRegion::Data | MemoryRegion::Ram => access != MemoryAccess::Execute,
+        };
+
+        match allowed {
+            true => Ok(()),
+            false => Err(VmError::ProtectionFault { address, region, access }),
+        }
     }
 }
 
 impl Memory for DefaultMemory {
-    fn write_u8(&mut self, address: u16, value: u8) -> Result<(), String> {
+    fn write_u8(&mut self, address: u16, value: u8) -> Result<(), VmError> {
+        self.check_access(address, MemoryAccess::Write)?;
         self.memory[address as usize] = value;
         Ok(())
     }
 
-    fn read_u8(&self, address: u16) -> Result<u8, String> {
+    fn read_u8(&self, address: u16) -> Result<u8, VmError> {
+        self.check_access(address, MemoryAccess::Read)?;
         let value = self.memory[address as usize];
         Ok(value)
     }
 
+    fn fetch_u8(&self, address: u16) -> Result<u8, VmError> {
+        self.check_access(address, MemoryAccess::Execute)?;
+        Ok(self.memory[address as usize])
+    }
+
+    fn stack_write_u8(&mut self, address: u16, value: u8) -> Result<(), VmError> {
+        self.memory[address as usize] = value;
+        Ok(())
+    }
+
+    fn stack_read_u8(&self, address: u16) -> Result<u8, VmError> {
+        Ok(self.memory[address as usize])
+    }
+
     fn stack_start_address(&self) -> u16 {
         self.stack_start_address
     }
@@ -92,11 +256,306 @@ impl Memory for DefaultMemory {
         self.ram_start_address
     }
 
+    fn code_size(&self) -> u16 {
+        self.code_size
+    }
+
+    fn is_protection_enabled(&self) -> bool {
+        self.protection
+    }
+
     fn dump_memory(&self) -> Vec<u8> {
         self.memory.clone()
     }
 
     fn dump_memory_range(&self, start: u16, end: u16) -> Vec<u8> {
-        self.memory[start as usize..end as usize].to_vec()
+        self.memory.get(start as usize..=end as usize).map_or(vec![], |bytes| bytes.to_vec())
+    }
+
+    fn restore_memory(&mut self, image: &[u8]) -> Result<(), VmError> {
+        if image.len() != self.memory.len() {
+            return Err(VmError::InvalidSnapshot(format!("Memory image size {} doesn't match {}", image.len(), self.memory.len())));
+        }
+        self.memory.copy_from_slice(image);
+        Ok(())
+    }
+}
+
+// Peripheral mapped into a MemoryBus. Offsets are relative to the start of the mapping.
+// MemoryFault errors returned with an offset are reported by the bus with the absolute address.
+pub trait Device {
+    fn read_u8(&self, offset: u16) -> Result<u8, VmError>;
+    fn write_u8(&mut self, offset: u16, value: u8) -> Result<(), VmError>;
+
+    // Side effect free read used for memory dumps
+    fn peek_u8(&self, offset: u16) -> u8 {
+        self.read_u8(offset).unwrap_or(0)
+    }
+
+    // Loads device contents from a memory image, e.g. when restoring a snapshot
+    fn restore(&mut self, image: &[u8]) -> Result<(), VmError> {
+        for (offset, value) in image.iter().enumerate() {
+            self.write_u8(offset as u16, *value)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram { data: vec![0u8; size] }
+    }
+}
+
+// Offsets past the end of the device are memory faults, the bus doesn't know device sizes
+impl Device for Ram {
+    fn read_u8(&self, offset: u16) -> Result<u8, VmError> {
+        match self.data.get(offset as usize) {
+            Some(value) => Ok(*value),
+            None => Err(VmError::MemoryFault { address: offset }),
+        }
+    }
+
+    fn write_u8(&mut self, offset: u16, value: u8) -> Result<(), VmError> {
+        match self.data.get_mut(offset as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(VmError::MemoryFault { address: offset }),
+        }
+    }
+}
+
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(data: Vec<u8>) -> Rom {
+        Rom { data }
+    }
+}
+
+impl Device for Rom {
+    fn read_u8(&self, offset: u16) -> Result<u8, VmError> {
+        match self.data.get(offset as usize) {
+            Some(value) => Ok(*value),
+            None => Err(VmError::MemoryFault { address: offset }),
+        }
+    }
+
+    fn write_u8(&mut self, offset: u16, _value: u8) -> Result<(), VmError> {
+        Err(VmError::MemoryFault { address: offset })
+    }
+
+    fn restore(&mut self, image: &[u8]) -> Result<(), VmError> {
+        if image.len() != self.data.len() {
+            return Err(VmError::InvalidSnapshot(format!("Memory image size {} doesn't match {}", image.len(), self.data.len())));
+        }
+        self.data.copy_from_slice(image);
+        Ok(())
+    }
+}
+
+// Single byte port. Writing outputs a byte, reading returns the next input byte or 0 at the end of input.
+pub struct ConsolePort {
+    input: RefCell<Box<dyn Read>>,
+    output: Box<dyn Write>,
+}
+
+impl ConsolePort {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> ConsolePort {
+        ConsolePort { input: RefCell::new(input), output }
+    }
+}
+
+impl Device for ConsolePort {
+    fn read_u8(&self, _offset: u16) -> Result<u8, VmError> {
+        let mut byte = [0u8; 1];
+        match self.input.borrow_mut().read(&mut byte) {
+            Ok(0) => Ok(0),
+            Ok(_) => Ok(byte[0]),
+            Err(err) => Err(VmError::Interrupt(format!("Console read failed - {}", err))),
+        }
+    }
+
+    fn write_u8(&mut self, _offset: u16, value: u8) -> Result<(), VmError> {
+        match self.output.write_all(&[value]).and_then(|_| self.output.flush()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(VmError::Interrupt(format!("Console write failed - {}", err))),
+        }
+    }
+
+    fn peek_u8(&self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn restore(&mut self, _image: &[u8]) -> Result<(), VmError> {
+        Ok(())
+    }
+}
+
+// 4 byte little-endian counter of milliseconds elapsed since creation. Any write resets it.
+pub struct Timer {
+    start: Instant,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { start: Instant::now() }
+    }
+}
+
+impl Device for Timer {
+    fn read_u8(&self, offset: u16) -> Result<u8, VmError> {
+        let elapsed = self.start.elapsed().as_millis() as u32;
+        Ok(elapsed.to_le_bytes().get(offset as usize).copied().unwrap_or(0))
+    }
+
+    fn write_u8(&mut self, _offset: u16, _value: u8) -> Result<(), VmError> {
+        self.start = Instant::now();
+        Ok(())
+    }
+
+    fn restore(&mut self, _image: &[u8]) -> Result<(), VmError> {
+        Ok(())
+    }
+}
+
+struct Mapping {
+    start: u16,
+    // Exclusive, can be 0x10000 for a mapping that reaches the top of memory
+    end: usize,
+    device: Box<dyn Device>,
+}
+
+// Memory composed of devices mapped to address ranges. Accessing an unmapped address is a memory fault.
+pub struct MemoryBus {
+    mappings: Vec<Mapping>,
+    stack_start_address: u16,
+    call_stack_start_address: u16,
+    ram_start_address: u16,
+}
+
+impl MemoryBus {
+    pub fn new(stack_start_address: u16, call_stack_start_address: u16, ram_start_address: u16) -> MemoryBus {
+        MemoryBus { mappings: vec![], stack_start_address, call_stack_start_address, ram_start_address }
+    }
+
+    // Same layout as DefaultMemory with code mapped as ROM and both stacks as RAM.
+    // Everything from ram_start_address upwards is left for the caller to map.
+    pub fn new_with_code(code: Vec<u8>) -> Result<MemoryBus, VmError> {
+        if code.len() + VM_STACK_SIZE * 2 > u16::MAX as usize {
+            return Err(VmError::ImageTooLarge { size: code.len(), limit: u16::MAX as usize - VM_STACK_SIZE * 2 });
+        }
+
+        let stack_start_address = code.len() as u16;
+        let call_stack_start_address = stack_start_address + VM_STACK_SIZE as u16;
+        let ram_start_address = call_stack_start_address + VM_STACK_SIZE as u16;
+
+        let mut bus = MemoryBus::new(stack_start_address, call_stack_start_address, ram_start_address);
+        if !code.is_empty() {
+            let code_size = code.len();
+            bus.map(0x0000, code_size, Box::new(Rom::new(code)))?;
+        }
+        bus.map(stack_start_address, VM_STACK_SIZE * 2, Box::new(Ram::new(VM_STACK_SIZE * 2)))?;
+
+        Ok(bus)
+    }
+
+    pub fn map(&mut self, start: u16, size: usize, device: Box<dyn Device>) -> Result<(), VmError> {
+        let end = start as usize + size;
+        if size == 0 || end > VM_MAX_IMAGE_SIZE {
+            return Err(VmError::MemoryFault { address: start });
+        }
+        if let Some(mapping) = self.mappings.iter().find(|mapping| (start as usize) < mapping.end && (mapping.start as usize) < end) {
+            return Err(VmError::MemoryFault { address: start.max(mapping.start) });
+        }
+
+        self.mappings.push(Mapping { start, end, device });
+        Ok(())
+    }
+
+    fn find_mapping(&self, address: u16) -> Result<&Mapping, VmError> {
+        match self.mappings.iter().find(|mapping| mapping.start <= address && (address as usize) < mapping.end) {
+            Some(mapping) => Ok(mapping),
+            None => Err(VmError::MemoryFault { address }),
+        }
+    }
+
+    fn find_mapping_mut(&mut self, address: u16) -> Result<&mut Mapping, VmError> {
+        match self.mappings.iter_mut().find(|mapping| mapping.start <= address && (address as usize) < mapping.end) {
+            Some(mapping) => Ok(mapping),
+            None => Err(VmError::MemoryFault { address }),
+        }
+    }
+
+    // Unmapped addresses read as 0
+    fn peek_u8(&self, address: u16) -> u8 {
+        match self.find_mapping(address) {
+            Ok(mapping) => mapping.device.peek_u8(address - mapping.start),
+            Err(_) => 0,
+        }
+    }
+
+    fn rebase_error(start: u16, error: VmError) -> VmError {
+        match error {
+            VmError::MemoryFault { address } => VmError::MemoryFault { address: start.wrapping_add(address) },
+            _ => error,
+        }
+    }
+}
+
+impl Memory for MemoryBus {
+    fn write_u8(&mut self, address: u16, value: u8) -> Result<(), VmError> {
+        let mapping = self.find_mapping_mut(address)?;
+        let start = mapping.start;
+        mapping.device.write_u8(address - start, value).map_err(|error| MemoryBus::rebase_error(start, error))
+    }
+
+    fn read_u8(&self, address: u16) -> Result<u8, VmError> {
+        let mapping = self.find_mapping(address)?;
+        mapping.device.read_u8(address - mapping.start).map_err(|error| MemoryBus::rebase_error(mapping.start, error))
+    }
+
+    fn stack_start_address(&self) -> u16 {
+        self.stack_start_address
+    }
+
+    fn call_stack_start_address(&self) -> u16 {
+        self.call_stack_start_address
+    }
+
+    fn ram_start_address(&self) -> u16 {
+        self.ram_start_address
+    }
+
+    fn dump_memory(&self) -> Vec<u8> {
+        (0..VM_MAX_IMAGE_SIZE).map(|address| self.peek_u8(address as u16)).collect()
+    }
+
+    fn dump_memory_range(&self, start: u16, end: u16) -> Vec<u8> {
+        (start..=end).map(|address| self.peek_u8(address)).collect()
+    }
+
+    fn restore_memory(&mut self, image: &[u8]) -> Result<(), VmError> {
+        if image.len() != VM_MAX_IMAGE_SIZE {
+            return Err(VmError::InvalidSnapshot(format!("Memory image size {} doesn't match {}", image.len(), VM_MAX_IMAGE_SIZE)));
+        }
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.restore(&image[mapping.start as usize..mapping.end])?;
+        }
+        Ok(())
     }
 }
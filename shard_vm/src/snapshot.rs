@@ -0,0 +1,170 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::convert::TryInto;
+use crate::error::VmError;
+use crate::memory::check_layout;
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"SHSN";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// Complete VM state. Serialized format (all values little-endian):
+//   magic "SHSN", version u16,
+//   pc u16, entry u16, sp u8, csp u8, reg_a u8, reg_b u8,
+//   has_fuel u8, fuel u64,
+//   code_size u16, stack_start_address u16, call_stack_start_address u16, ram_start_address u16,
+//   protected u8,
+//   breakpoint_count u16, breakpoints [u16],
+//   memory_size u32, memory [u8]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSnapshot {
+    pub pc: u16,
+    pub entry: u16,
+    pub sp: u8,
+    pub csp: u8,
+    pub reg_a: u8,
+    pub reg_b: u8,
+    pub fuel: Option<u64>,
+    // Instructions end here, data follows up to the stack
+    pub code_size: u16,
+    pub stack_start_address: u16,
+    pub call_stack_start_address: u16,
+    pub ram_start_address: u16,
+    // Memory protection is enabled
+    pub protected: bool,
+    pub breakpoints: Vec<u16>,
+    pub memory: Vec<u8>,
+}
+
+impl VmSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        bytes.push(self.sp);
+        bytes.push(self.csp);
+        bytes.push(self.reg_a);
+        bytes.push(self.reg_b);
+        bytes.push(self.fuel.is_some() as u8);
+        bytes.extend_from_slice(&self.fuel.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&self.code_size.to_le_bytes());
+        bytes.extend_from_slice(&self.stack_start_address.to_le_bytes());
+        bytes.extend_from_slice(&self.call_stack_start_address.to_le_bytes());
+        bytes.extend_from_slice(&self.ram_start_address.to_le_bytes());
+        bytes.push(self.protected as u8);
+        bytes.extend_from_slice(&(self.breakpoints.len() as u16).to_le_bytes());
+        for breakpoint in self.breakpoints.iter() {
+            bytes.extend_from_slice(&breakpoint.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<VmSnapshot, VmError> {
+        let mut reader = SnapshotReader { bytes, offset: 0 };
+
+        if reader.read_bytes(4)? != SNAPSHOT_MAGIC {
+            return Err(VmError::InvalidSnapshot(String::from("Not a VM snapshot")));
+        }
+        let version = reader.read_u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(VmError::InvalidSnapshot(format!("Unsupported snapshot version {}", version)));
+        }
+
+        let pc = reader.read_u16()?;
+        let entry = reader.read_u16()?;
+        let sp = reader.read_u8()?;
+        let csp = reader.read_u8()?;
+        let reg_a = reader.read_u8()?;
+        let reg_b = reader.read_u8()?;
+        let has_fuel = reader.read_u8()? != 0;
+        let fuel = reader.read_u64()?;
+        let code_size = reader.read_u16()?;
+        let stack_start_address = reader.read_u16()?;
+        let call_stack_start_address = reader.read_u16()?;
+        let ram_start_address = reader.read_u16()?;
+        check_layout(code_size, stack_start_address, call_stack_start_address, ram_start_address)?;
+        let protected = reader.read_u8()? != 0;
+
+        let breakpoint_count = reader.read_u16()?;
+        let mut breakpoints = vec![];
+        for _ in 0..breakpoint_count {
+            breakpoints.push(reader.read_u16()?);
+        }
+
+        let memory_size = reader.read_u32()? as usize;
+        let memory = reader.read_bytes(memory_size)?.to_vec();
+
+        if reader.offset != bytes.len() {
+            return Err(VmError::InvalidSnapshot(String::from("Unexpected data after memory image")));
+        }
+
+        Ok(VmSnapshot {
+            pc,
+            entry,
+            sp,
+            csp,
+            reg_a,
+            reg_b,
+            fuel: if has_fuel { Some(fuel) } else { None },
+            code_size,
+            stack_start_address,
+            call_stack_start_address,
+            ram_start_address,
+            protected,
+            breakpoints,
+            memory,
+        })
+    }
+}
+
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], VmError> {
+        if self.bytes.len() - self.offset < size {
+            return Err(VmError::InvalidSnapshot(String::from("Snapshot is truncated")));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + size];
+        self.offset += size;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, VmError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, VmError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+}
@@ -17,10 +17,19 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
-use crate::vm::{VM, InterruptType};
-
-
-fn interrupt_handler(_vm: &mut VM, _interrupt_type: InterruptType) {}
+use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+use shard_core::image::{self, ImageHeader, IMAGE_HEADER_SIZE, ISA_VERSION};
+use shard_core::opcodes::Opcode;
+use crate::error::VmError;
+use crate::fuel::CostTable;
+use crate::memory::{DefaultMemory, MemoryAccess, MemoryRegion, MemoryBus, Memory, Device, Ram, Rom, ConsolePort, Timer};
+use crate::snapshot::VmSnapshot;
+use crate::vm::{VM, InterruptType, InterruptHandler, ExitStatus, load_image};
+
+
+fn interrupt_handler(_vm: &mut VM, _interrupt_type: InterruptType) -> Result<(), VmError> {
+    Ok(())
+}
 
 #[test]
 fn execution_tests() {
@@ -28,7 +37,7 @@ fn execution_tests() {
         let code = shard_compiler::compile_from_asm(vec![String::from("  nop")]).unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
     }
@@ -44,7 +53,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -61,7 +70,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -78,7 +87,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -98,7 +107,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -122,7 +131,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -141,7 +150,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -161,7 +170,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -181,7 +190,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -201,7 +210,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -221,7 +230,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -241,7 +250,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -261,7 +270,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -281,7 +290,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -301,7 +310,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -321,7 +330,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -341,7 +350,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -361,7 +370,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -381,7 +390,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -401,7 +410,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -421,7 +430,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -441,7 +450,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -461,7 +470,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -483,7 +492,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -503,7 +512,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -523,7 +532,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -543,7 +552,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -563,7 +572,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -580,7 +589,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -597,7 +606,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -614,7 +623,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -631,7 +640,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -648,7 +657,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -665,7 +674,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -682,7 +691,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -699,7 +708,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -715,7 +724,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -732,7 +741,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -749,7 +758,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -766,7 +775,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -783,7 +792,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -800,7 +809,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -817,7 +826,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -834,7 +843,7 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
@@ -851,10 +860,811 @@ fn execution_tests() {
         .unwrap();
 
         let mut vm = VM::new(code).unwrap();
-        if let Err(err) = vm.execute(interrupt_handler) {
+        if let Err(err) = vm.execute(&mut interrupt_handler) {
             println!("Error: {}", err);
         }
 
         assert_eq!(vm.get_reg_a(), 0x80);
     }
 }
+
+#[test]
+fn error_tests() {
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  nop"),
+            String::from("  pop"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+
+        assert_eq!(err.root(), &VmError::StackUnderflow);
+        assert_eq!(err.pc(), Some(0x01));
+        assert_eq!(err.opcode(), Some(Opcode::Pop));
+    }
+    {
+        let code = vec![Opcode::Nop as u8, 0xff];
+
+        let mut vm = VM::new(code).unwrap();
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+
+        assert_eq!(err, VmError::UnknownOpcode { byte: 0xff, pc: 0x01 });
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("main:"),
+            String::from("  call main"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+
+        assert_eq!(err.root(), &VmError::CallStackOverflow);
+        assert_eq!(err.opcode(), Some(Opcode::Call));
+    }
+    {
+        let code = vec![0x00; u16::MAX as usize + 1];
+
+        match VM::new(code) {
+            Ok(_) => panic!("oversized image should be rejected"),
+            Err(err) => assert_eq!(err, VmError::ImageTooLarge { size: u16::MAX as usize + 1, limit: u16::MAX as usize - 512 }),
+        }
+    }
+    {
+        // The largest image that still leaves room for both stacks
+        let limit = u16::MAX as usize - 512;
+
+        let mut vm = VM::new(vec![0x00; limit]).unwrap();
+        assert_eq!(vm.get_memory_mut().ram_start_address() as usize, u16::MAX as usize);
+
+        match VM::new(vec![0x00; limit + 1]) {
+            Ok(_) => panic!("oversized image should be rejected"),
+            Err(err) => assert_eq!(err, VmError::ImageTooLarge { size: limit + 1, limit }),
+        }
+    }
+}
+
+struct RecordingHandler {
+    values: Vec<u8>,
+}
+
+impl InterruptHandler for RecordingHandler {
+    fn handle_interrupt(&mut self, vm: &mut VM, interrupt_type: InterruptType) -> Result<(), VmError> {
+        if let InterruptType::SysCall = interrupt_type {
+            self.values.push(vm.stack_pop()?);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn interrupt_handler_tests() {
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  sys"),
+            String::from("  sys"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut syscall_count = 0;
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut |_vm: &mut VM, _interrupt_type: InterruptType| {
+            syscall_count += 1;
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(syscall_count, 2);
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x0a"),
+            String::from("  sys"),
+            String::from("  push 0x0b"),
+            String::from("  sys"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut handler = RecordingHandler { values: vec![] };
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut handler).unwrap();
+
+        assert_eq!(handler.values, vec![0x0a, 0x0b]);
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  sys"),
+            String::from("  push 0xff"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        let result = vm.execute(&mut |_vm: &mut VM, _interrupt_type: InterruptType| {
+            Err(VmError::Interrupt(String::from("exit")))
+        });
+
+        assert_eq!(result, Err(VmError::Interrupt(String::from("exit"))));
+        assert_eq!(vm.get_reg_a(), 0x00);
+    }
+}
+
+fn run_binary_op(lhs: u8, rhs: u8, op: &str) -> (u8, Vec<VmError>) {
+    let code = shard_compiler::compile_from_asm(vec![
+        format!("  push {:#04x}", lhs),
+        format!("  push {:#04x}", rhs),
+        format!("  {}", op),
+        String::from("  set_reg_a"),
+        String::from("  return"),
+    ])
+    .unwrap();
+
+    let mut traps = vec![];
+    let mut vm = VM::new(code).unwrap();
+    vm.execute(&mut |_vm: &mut VM, interrupt_type: InterruptType| {
+        if let InterruptType::Trap(error) = interrupt_type {
+            traps.push(error);
+        }
+        Ok(())
+    }).unwrap();
+
+    (vm.get_reg_a(), traps)
+}
+
+#[test]
+fn arithmetic_fault_tests() {
+    for op in ["div_u", "div_s", "rem_u", "rem_s"].iter() {
+        let (result, traps) = run_binary_op(0x10, 0x00, op);
+
+        assert_eq!(result, 0x00);
+        assert_eq!(traps.len(), 1);
+        assert_eq!(traps[0].root(), &VmError::DivisionByZero);
+        assert_eq!(traps[0].pc(), Some(0x04));
+        assert_eq!(traps[0].opcode(), Opcode::from_string(op));
+    }
+
+    // Signed overflow wraps
+    assert_eq!(run_binary_op(0x80, 0xff, "div_s"), (0x80, vec![]));
+    assert_eq!(run_binary_op(0x80, 0xff, "rem_s"), (0x00, vec![]));
+
+    // Shifting by 8 or more shifts out every bit
+    assert_eq!(run_binary_op(0xff, 0x08, "shl"), (0x00, vec![]));
+    assert_eq!(run_binary_op(0xff, 0xff, "shr_u"), (0x00, vec![]));
+    assert_eq!(run_binary_op(0x80, 0x08, "shr_s"), (0xff, vec![]));
+    assert_eq!(run_binary_op(0x40, 0x80, "shr_s"), (0x00, vec![]));
+    assert_eq!(run_binary_op(0x80, 0x07, "shr_s"), (0xff, vec![]));
+    assert_eq!(run_binary_op(0x81, 0x09, "rotl"), (0x03, vec![]));
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x80"),
+            String::from("  abs"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.get_reg_a(), 0x80);
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x01"),
+            String::from("  push 0x00"),
+            String::from("  div_u"),
+            String::from("  push 0xff"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        let result = vm.execute(&mut |_vm: &mut VM, interrupt_type: InterruptType| {
+            match interrupt_type {
+                InterruptType::Trap(error) => Err(error),
+                _ => Ok(()),
+            }
+        });
+
+        let err = result.unwrap_err();
+        assert_eq!(err.root(), &VmError::DivisionByZero);
+        assert_eq!(err.pc(), Some(0x04));
+        assert_eq!(vm.get_reg_a(), 0x00);
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x01"),
+            String::from("  push 0x00"),
+            String::from("  div_u"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut interrupts = vec![];
+        let mut vm = VM::new(code).unwrap();
+        vm.set_breakpoint(0x05);
+        vm.execute(&mut |_: &mut VM, interrupt_type: InterruptType| {
+            match interrupt_type {
+                InterruptType::Trap(_) => interrupts.push("trap"),
+                InterruptType::Breakpoint => interrupts.push("breakpoint"),
+                _ => {}
+            }
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(interrupts, vec!["trap", "breakpoint"]);
+    }
+}
+
+#[test]
+fn fuel_tests() {
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("loop:"),
+            String::from("  jump loop"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.set_fuel(Some(1000));
+
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(vm.get_fuel(), Some(0));
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x01"),
+            String::from("  push 0x02"),
+            String::from("  add"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.set_fuel(Some(2));
+
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(vm.get_reg_a(), 0x00);
+
+        // Resume where execution stopped
+        vm.add_fuel(2);
+        assert_eq!(vm.continue_execution(&mut interrupt_handler), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(vm.get_reg_a(), 0x03);
+
+        vm.add_fuel(1);
+        assert_eq!(vm.continue_execution(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_fuel(), Some(0));
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0x02"),
+            String::from("  push 0x03"),
+            String::from("  pow"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut cost_table = CostTable::new();
+        cost_table.set_cost(Opcode::Pow, 10);
+
+        let mut vm = VM::new(code).unwrap();
+        vm.set_cost_table(cost_table);
+        vm.set_fuel(Some(11));
+
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(vm.get_fuel(), Some(9));
+
+        vm.add_fuel(3);
+        assert_eq!(vm.continue_execution(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_reg_a(), 0x08);
+        assert_eq!(vm.get_fuel(), Some(0));
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![String::from("  return")]).unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_fuel(), None);
+    }
+}
+
+#[test]
+fn snapshot_tests() {
+    let code = shard_compiler::compile_from_asm(vec![
+        String::from("  push 0x05"),
+        String::from("  store8 0xaaaa"),
+        String::from("  call count"),
+        String::from("  return"),
+        String::from("count:"),
+        String::from("  load8 0xaaaa"),
+        String::from("  push 0x01"),
+        String::from("  sub"),
+        String::from("  store8 0xaaaa"),
+        String::from("  get_reg_a"),
+        String::from("  push 0x02"),
+        String::from("  add"),
+        String::from("  set_reg_a"),
+        String::from("  load8 0xaaaa"),
+        String::from("  eqz done"),
+        String::from("  jump count"),
+        String::from("done:"),
+        String::from("  return"),
+    ])
+    .unwrap();
+
+    let mut vm = VM::new(code).unwrap();
+    vm.set_breakpoint(0x0010);
+    vm.set_breakpoint(0x0004);
+    vm.set_fuel(Some(20));
+    assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::OutOfFuel));
+
+    let snapshot = vm.snapshot();
+    assert_eq!(snapshot.breakpoints, vec![0x0004, 0x0010]);
+    assert_eq!(snapshot.fuel, Some(0));
+
+    let bytes = snapshot.to_bytes();
+    let decoded = VmSnapshot::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    // Finish the original run
+    vm.set_fuel(None);
+    assert_eq!(vm.continue_execution(&mut interrupt_handler), Ok(ExitStatus::Done));
+    assert_eq!(vm.get_reg_a(), 0x0a);
+    assert_eq!(vm.peek_memory(0xaaaa), Ok(0x00));
+
+    // Resume the checkpoint in a fresh VM
+    let mut restored = VM::from_snapshot(&decoded).unwrap();
+    assert_eq!(restored.snapshot(), snapshot);
+    restored.set_fuel(None);
+    assert_eq!(restored.continue_execution(&mut interrupt_handler), Ok(ExitStatus::Done));
+    assert_eq!(restored.get_reg_a(), 0x0a);
+    assert_eq!(restored.dump_memory(), vm.dump_memory());
+
+    // Rewind the original VM
+    vm.restore(&decoded).unwrap();
+    assert_eq!(vm.snapshot(), snapshot);
+
+    let mut other = VM::new(vec![0x00]).unwrap();
+    assert!(other.restore(&snapshot).is_err());
+
+    assert!(VmSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(VmSnapshot::from_bytes(b"SHSX").is_err());
+
+    // Snapshots of another format version are rejected
+    let mut other_version = bytes.clone();
+    other_version[4..6].copy_from_slice(&2u16.to_le_bytes());
+    assert!(VmSnapshot::from_bytes(&other_version).is_err());
+
+    // The entry point survives a round trip and is used when the VM is reset
+    let mut vm = VM::new(vec![0x00; 8]).unwrap();
+    vm.set_entry(0x0004);
+    let decoded = VmSnapshot::from_bytes(&vm.snapshot().to_bytes()).unwrap();
+    assert_eq!(decoded.entry, 0x0004);
+
+    let mut restored = VM::from_snapshot(&decoded).unwrap();
+    assert_eq!(restored.get_entry(), 0x0004);
+    restored.reset();
+    assert_eq!(restored.snapshot().pc, 0x0004);
+
+    // Layouts DefaultMemory can't have are rejected instead of breaking stack accesses later
+    let mut bad_layout = bytes.clone();
+    bad_layout[25..27].copy_from_slice(&0xff80u16.to_le_bytes());
+    assert_eq!(VmSnapshot::from_bytes(&bad_layout), Err(VmError::InvalidSnapshot(String::from("Invalid memory layout"))));
+    let mut bad_layout = snapshot.clone();
+    bad_layout.stack_start_address = 0xff80;
+    assert!(VM::from_snapshot(&bad_layout).is_err());
+    bad_layout.stack_start_address = snapshot.stack_start_address;
+    bad_layout.code_size = snapshot.stack_start_address + 1;
+    assert!(VM::from_snapshot(&bad_layout).is_err());
+    assert!(DefaultMemory::new_with_layout(vec![0x00; 0x10000], 0x00, 0xfe00, 0xff00, 0xffff).is_err());
+}
+
+fn new_protected_vm(asm_source: Vec<&str>) -> VM {
+    let code = shard_compiler::compile_from_asm(asm_source.iter().map(|line| line.to_string()).collect()).unwrap();
+    let mut memory = DefaultMemory::new(code).unwrap();
+    memory.set_protection(true);
+    VM::new_with_custom_memory(Box::new(memory))
+}
+
+#[test]
+fn memory_protection_tests() {
+    {
+        let mut vm = new_protected_vm(vec![
+            "  push 0x01",
+            "  push 0x02",
+            "  call test",
+            "  return",
+            "test:",
+            "  add",
+            "  store8 0xaaaa",
+            "  load8 0xaaaa",
+            "  stack_get 0x01",
+            "  set_reg_a",
+            "  return",
+        ]);
+
+        vm.execute(&mut interrupt_handler).unwrap();
+        assert_eq!(vm.get_reg_a(), 0x03);
+    }
+    {
+        let mut vm = new_protected_vm(vec![
+            "  push 0xff",
+            "  store8 0x0000",
+            "  return",
+        ]);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x0000, region: MemoryRegion::Code, access: MemoryAccess::Write });
+        assert_eq!(err.pc(), Some(0x02));
+    }
+    {
+        let mut vm = new_protected_vm(vec![
+            "  load8 0x0000",
+            "  set_reg_a",
+            "  return",
+        ]);
+
+        vm.execute(&mut interrupt_handler).unwrap();
+        assert_eq!(vm.get_reg_a(), Opcode::Load8 as u8);
+    }
+    {
+        // Stack starts right after the 4 byte code segment
+        let mut vm = new_protected_vm(vec![
+            "  load8 0x00ff",
+            "  return",
+        ]);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x00ff, region: MemoryRegion::Stack, access: MemoryAccess::Read });
+    }
+    {
+        let mut vm = new_protected_vm(vec![
+            "  push 0x01",
+            "  store8 0x0200",
+            "  return",
+        ]);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x0200, region: MemoryRegion::CallStack, access: MemoryAccess::Write });
+    }
+    {
+        let mut vm = new_protected_vm(vec![
+            "  jump 0x8000",
+        ]);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x8000, region: MemoryRegion::Ram, access: MemoryAccess::Execute });
+    }
+    {
+        // Without protection the code segment is writable
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push 0xff"),
+            String::from("  store8 0x0000"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+        assert_eq!(vm.peek_memory(0x0000), Ok(0xff));
+    }
+    {
+        // Globals after the code are writable, the code itself is not
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  load8 counter"),
+            String::from("  push 0x01"),
+            String::from("  add"),
+            String::from("  store8 counter"),
+            String::from("  push 0xff"),
+            String::from("  store8 0x0000"),
+            String::from("  return"),
+            String::from("counter: 0x05"),
+        ])
+        .unwrap();
+        let code_size = code.len() - 1;
+
+        let mut memory = DefaultMemory::new_with_code_size(code, code_size).unwrap();
+        memory.set_protection(true);
+        assert_eq!(memory.region(code_size as u16 - 1), MemoryRegion::Code);
+        assert_eq!(memory.region(code_size as u16), MemoryRegion::Data);
+
+        let mut vm = VM::new_with_custom_memory(Box::new(memory));
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x0000, region: MemoryRegion::Code, access: MemoryAccess::Write });
+        assert_eq!(vm.peek_memory(code_size as u16), Ok(0x06));
+
+        assert!(DefaultMemory::new_with_code_size(vec![0x00; 4], 5).is_err());
+
+        // Code size and protection survive a snapshot
+        let snapshot = VmSnapshot::from_bytes(&vm.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot.code_size, code_size as u16);
+        assert!(snapshot.protected);
+
+        let mut restored = VM::from_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.get_memory_mut().code_size(), code_size as u16);
+        let err = restored.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::ProtectionFault { address: 0x0000, region: MemoryRegion::Code, access: MemoryAccess::Write });
+        assert_eq!(restored.peek_memory(code_size as u16), Ok(0x07));
+    }
+}
+
+#[derive(Clone)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn new_bus_vm(asm_source: Vec<&str>, input: &'static [u8], output: SharedBuffer) -> VM {
+    let code = shard_compiler::compile_from_asm(asm_source.iter().map(|line| line.to_string()).collect()).unwrap();
+    let mut bus = MemoryBus::new_with_code(code).unwrap();
+    let ram_start_address = bus.ram_start_address();
+    bus.map(ram_start_address, 0xff00 - ram_start_address as usize, Box::new(Ram::new(0xff00 - ram_start_address as usize))).unwrap();
+    bus.map(0xff00, 1, Box::new(ConsolePort::new(Box::new(input), Box::new(output)))).unwrap();
+    bus.map(0xff10, 4, Box::new(Timer::new())).unwrap();
+    VM::new_with_custom_memory(Box::new(bus))
+}
+
+#[test]
+fn memory_bus_tests() {
+    {
+        let output = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut vm = new_bus_vm(vec![
+            "  load8 0xff00",
+            "  push 0x01",
+            "  add",
+            "  store8 0xff00",
+            "  push 0x69",
+            "  store8 0xff00",
+            "  push 0x2a",
+            "  store8 0xaaaa",
+            "  load8 0xaaaa",
+            "  set_reg_a",
+            "  return",
+        ], b"G", output.clone());
+
+        vm.execute(&mut interrupt_handler).unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"Hi");
+        assert_eq!(vm.get_reg_a(), 0x2a);
+        assert_eq!(vm.peek_memory(0xaaaa), Ok(0x2a));
+    }
+    {
+        let output = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut vm = new_bus_vm(vec![
+            "  push 0x01",
+            "  store8 0x0001",
+            "  return",
+        ], b"", output);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::MemoryFault { address: 0x0001 });
+    }
+    {
+        let output = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut vm = new_bus_vm(vec![
+            "  load8 0xff05",
+            "  return",
+        ], b"", output);
+
+        let err = vm.execute(&mut interrupt_handler).unwrap_err();
+        assert_eq!(err.root(), &VmError::MemoryFault { address: 0xff05 });
+    }
+    {
+        let mut bus = MemoryBus::new_with_code(vec![Opcode::Return as u8]).unwrap();
+        assert!(bus.map(0x0000, 0x10, Box::new(Ram::new(0x10))).is_err());
+        assert!(bus.map(0xfff0, 0x11, Box::new(Ram::new(0x11))).is_err());
+        assert!(bus.map(0xfff0, 0x10, Box::new(Ram::new(0x10))).is_ok());
+        assert_eq!(bus.dump_memory().len(), 0x10000);
+        assert_eq!(bus.dump_memory_range(0xfff0, 0xffff).len(), 0x10);
+
+        let mut vm = VM::new_with_custom_memory(Box::new(bus));
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::Done));
+    }    {
+        // Mappings larger than their device fault past the end of the device
+        let mut bus = MemoryBus::new_with_code(vec![Opcode::Return as u8]).unwrap();
+        bus.map(0x8000, 0x10, Box::new(Ram::new(0x04))).unwrap();
+        bus.map(0x9000, 0x10, Box::new(Rom::new(vec![0x2a; 0x04]))).unwrap();
+
+        assert_eq!(bus.write_u8(0x8003, 0x01), Ok(()));
+        assert_eq!(bus.write_u8(0x8004, 0x01), Err(VmError::MemoryFault { address: 0x8004 }));
+        assert_eq!(bus.read_u8(0x8004), Err(VmError::MemoryFault { address: 0x8004 }));
+        assert_eq!(bus.read_u8(0x9003), Ok(0x2a));
+        assert_eq!(bus.read_u8(0x9004), Err(VmError::MemoryFault { address: 0x9004 }));
+
+        let mut rom = Rom::new(vec![0x00; 0x04]);
+        assert!(rom.restore(&[0x01; 0x04]).is_ok());
+        assert!(rom.restore(&[0x01; 0x10]).is_err());
+        assert_eq!(rom.read_u8(0x0000), Ok(0x01));
+    }
+}
+
+#[test]
+fn endianness_tests() {
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push_addr 0x1234"),
+            String::from("  store16 0xaaaa"),
+            String::from("  load16 0xaaaa"),
+            String::from("  set_reg_a"),
+            String::from("  set_reg_b"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.peek_memory(0xaaaa), Ok(0x34));
+        assert_eq!(vm.peek_memory(0xaaab), Ok(0x12));
+        // lsb is on top of the stack
+        assert_eq!(vm.get_reg_a(), 0x34);
+        assert_eq!(vm.get_reg_b(), 0x12);
+    }
+    {
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push_addr 0x5678"),
+            String::from("  push_addr 0xaaaa"),
+            String::from("  store16_c"),
+            String::from("  push_addr 0xaaaa"),
+            String::from("  load16_c"),
+            String::from("  store16 0xbbbb"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.dump_memory_range(0xaaaa, 0xaaab), vec![0x78, 0x56]);
+        assert_eq!(vm.dump_memory_range(0xbbbb, 0xbbbc), vec![0x78, 0x56]);
+        // End is included so the last byte can be dumped
+        assert_eq!(vm.dump_memory_range(0xfffe, 0xffff), vec![0x00, 0x00]);
+        assert_eq!(vm.dump_memory_range(0xbbbc, 0xbbbb), vec![]);
+    }
+    {
+        // Operand encoded by the compiler reads back as the same address through load16
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  jump main"),
+            String::from("main:"),
+            String::from("  load16 0x0001"),
+            String::from("  set_reg_a"),
+            String::from("  set_reg_b"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.get_reg_a(), 0x03);
+        assert_eq!(vm.get_reg_b(), 0x00);
+    }
+    {
+        // Address stored in memory can be used as a jump target
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push_addr target"),
+            String::from("  store16 0xaaaa"),
+            String::from("  load16 0xaaaa"),
+            String::from("  jump_c"),
+            String::from("  return"),
+            String::from("target:"),
+            String::from("  push 0xff"),
+            String::from("  set_reg_a"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.get_reg_a(), 0xff);
+    }
+    {
+        // 16-bit accesses wrap around at the top of memory
+        let code = shard_compiler::compile_from_asm(vec![
+            String::from("  push_addr 0xabcd"),
+            String::from("  store16 0xffff"),
+            String::from("  load16 0xffff"),
+            String::from("  set_reg_a"),
+            String::from("  set_reg_b"),
+            String::from("  return"),
+        ])
+        .unwrap();
+
+        let mut vm = VM::new(code).unwrap();
+        vm.execute(&mut interrupt_handler).unwrap();
+
+        assert_eq!(vm.peek_memory(0xffff), Ok(0xcd));
+        assert_eq!(vm.peek_memory(0x0000), Ok(0xab));
+        assert_eq!(vm.get_reg_a(), 0xcd);
+        assert_eq!(vm.get_reg_b(), 0xab);
+    }
+}
+
+#[test]
+fn image_tests() {
+    let code = shard_compiler::compile_from_asm(vec![
+        String::from("  push 0x07"),
+        String::from("  set_reg_a"),
+        String::from("  return"),
+        String::from("data: 0x01 0x02"),
+    ])
+    .unwrap();
+    let image = image::write_image(&code, 4, 0, 0x0100).unwrap();
+    assert_eq!(&image[..4], b"SHRD");
+    assert_eq!(image.len(), IMAGE_HEADER_SIZE + code.len());
+    {
+        let (header, payload) = load_image(&image).unwrap();
+        let header = header.unwrap();
+        assert_eq!((header.code_size, header.data_size, header.required_ram), (4, 2, 0x0100));
+        assert_eq!(payload, code);
+        assert_eq!(ImageHeader::from_bytes(&image).unwrap(), header);
+
+        let mut vm = VM::from_image(&image).unwrap();
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_reg_a(), 0x07);
+
+        // Execution starts at the entry point of the header
+        let mut vm = VM::from_image(&image::write_image(&code, 4, 3, 0).unwrap()).unwrap();
+        assert_eq!(vm.get_entry(), 3);
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_reg_a(), 0x00);
+        assert_eq!(vm.execute_from(0x0000, &mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_reg_a(), 0x07);
+
+        // Raw images are loaded as they are
+        assert_eq!(load_image(&code).unwrap(), (None, code.clone()));
+        let mut vm = VM::from_image(&code).unwrap();
+        assert_eq!(vm.execute(&mut interrupt_handler), Ok(ExitStatus::Done));
+        assert_eq!(vm.get_reg_a(), 0x07);
+        assert_eq!(vm.get_code_size(), code.len() as u16);
+
+        // Code size of the symbols overrides the header
+        let vm = VM::from_image(&image).unwrap();
+        assert_eq!((vm.get_code_size(), vm.snapshot().protected), (4, false));
+        let vm = VM::from_image_with_options(&image, Some(2), true).unwrap();
+        assert_eq!((vm.get_code_size(), vm.snapshot().protected), (2, true));
+        assert!(VM::from_image_with_options(&image, Some(7), false).is_err());
+    }
+    {
+        let invalid_image = |message: &str| Err(VmError::InvalidImage(message.to_string()));
+
+        let mut corrupted = image.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(load_image(&corrupted), Err(VmError::InvalidImage(message)) if message.ends_with("image is corrupted")));
+
+        assert_eq!(load_image(&image[..10]), invalid_image("image header is truncated"));
+        assert_eq!(load_image(&image[..image.len() - 1]), invalid_image("image has 5 bytes of code and data, header expects 6"));
+        assert_eq!(load_image(&image::write_image(&code, 4, 4, 0).unwrap()), invalid_image("entry point 0x0004 is outside of the code"));
+        assert_eq!(load_image(&image::write_image(&code, 4, 0, 0xffff).unwrap()), invalid_image("program needs 65535 bytes of ram, 65018 available"));
+
+        let mut header = ImageHeader::new(&code, 4, 0, 0).unwrap();
+        header.isa_version = ISA_VERSION + 1;
+        let mut other_isa = header.to_bytes();
+        other_isa.extend_from_slice(&code);
+        assert_eq!(load_image(&other_isa), invalid_image("image is built for ISA version 2, expected 1"));
+        assert_eq!(VmError::InvalidImage(String::from("x")).to_string(), "Invalid image: x");
+
+        // Code size has to fit into the payload
+        assert_eq!(ImageHeader::new(&code, 7, 0, 0), Err(String::from("code size 7 is larger than the 6 bytes of code and data")));
+        assert_eq!(image::write_image(&code, 6, 0, 0).unwrap().len(), IMAGE_HEADER_SIZE + 6);
+    }
+}
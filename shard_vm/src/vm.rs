@@ -18,10 +18,18 @@
 //
 
 use std::{convert::TryFrom, collections::HashSet};
+use shard_core::image::{self, ImageHeader};
 use shard_core::opcodes::Opcode;
+use crate::error::VmError;
+use crate::fuel::CostTable;
 use crate::memory::{Memory, DefaultMemory};
+use crate::snapshot::VmSnapshot;
 
 
+// All 16-bit values are little-endian - the least significant byte is stored at the lower address.
+// This applies to instruction operands (see shard_compiler::instruction), load16/store16 and
+// addresses on the data and call stacks. Stacks grow downwards, so the lsb of a pushed address
+// is on top of the stack.
 pub const VM_ADDRESS_SIZE: usize = 2;
 pub const VM_OPERAND_SIZE: usize = 1;
 pub const VM_VALUE_SIZE: usize = VM_OPERAND_SIZE;
@@ -29,6 +37,21 @@ pub const VM_STACK_SIZE: usize = u8::MAX as usize + 1;
 pub const VM_CALL_STACK_SIZE: usize = (u8::MAX as usize + 1) * 2;
 pub const VM_MAX_IMAGE_SIZE: usize = u16::MAX as usize + 1;
 
+// Validates the header of an image written by shardc and checks that the program fits into memory.
+// Returns the header, None for raw images without one, and the code followed by data.
+pub fn load_image(image: &[u8]) -> Result<(Option<ImageHeader>, Vec<u8>), VmError> {
+    if !image::has_header(image) {
+        return Ok((None, image.to_vec()));
+    }
+
+    let (header, payload) = image::read_image(image).map_err(VmError::InvalidImage)?;
+    let available_ram = VM_MAX_IMAGE_SIZE.saturating_sub(payload.len() + VM_STACK_SIZE * 2);
+    if header.required_ram as usize > available_ram {
+        return Err(VmError::InvalidImage(format!("program needs {} bytes of ram, {} available", header.required_ram, available_ram)));
+    }
+    Ok((Some(header), payload.to_vec()))
+}
+
 pub struct VM {
     memory: Box<dyn Memory>,
     sp: u8,
@@ -36,32 +59,150 @@ pub struct VM {
     pc: u16,
     reg_a: u8,
     reg_b: u8,
+    // Address reset sets pc to
+    entry: u16,
     breakpoints: HashSet<u16>,
+    // Remaining instruction budget. None means unlimited execution.
+    fuel: Option<u64>,
+    cost_table: CostTable,
 }
 
 pub enum ExecutionStatus {
     Continue,
     SysCall,
     Breakpoint,
+    Trap(VmError),
+    OutOfFuel,
     Done,
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExitStatus {
+    Done,
+    // Fuel ran out before the next instruction. Add more fuel and call continue_execution to resume.
+    OutOfFuel,
+}
+
 pub enum InterruptType {
     SysCall,
     Breakpoint,
+    // Recoverable fault raised by the guest, e.g. division by zero. The faulting instruction
+    // has already completed with a defined result, so returning Ok from the handler resumes
+    // execution at the next instruction.
+    Trap(VmError),
+}
+
+// Receives interrupts raised by the VM. Implement this on a struct to keep host state
+// (I/O handles, buffers, counters) between interrupts. Returning an error stops execution.
+pub trait InterruptHandler {
+    fn handle_interrupt(&mut self, vm: &mut VM, interrupt_type: InterruptType) -> Result<(), VmError>;
+}
+
+impl<F> InterruptHandler for F where F: FnMut(&mut VM, InterruptType) -> Result<(), VmError> {
+    fn handle_interrupt(&mut self, vm: &mut VM, interrupt_type: InterruptType) -> Result<(), VmError> {
+        self(vm, interrupt_type)
+    }
 }
 
 impl VM {
-    pub fn new(code: Vec<u8>) -> Result<VM, String> {
+    pub fn new(code: Vec<u8>) -> Result<VM, VmError> {
         let memory = Box::new(DefaultMemory::new(code)?);
-        Ok(VM { sp: 0xff, csp: 0xff, pc: 0x00, reg_a: 0x00, reg_b: 0x00, memory, breakpoints: HashSet::new() })
+        Ok(VM::new_with_custom_memory(memory))
+    }
+
+    // Creates a VM from an image with or without a header, see load_image. Execution starts at the
+    // entry point of the header.
+    pub fn from_image(image: &[u8]) -> Result<VM, VmError> {
+        VM::from_image_with_options(image, None, false)
+    }
+
+    // Code size overrides the one of the header, e.g. from symbols of a raw image which is otherwise
+    // all code. Protect turns on memory protection, see DefaultMemory::set_protection.
+    pub fn from_image_with_options(image: &[u8], code_size: Option<u16>, protect: bool) -> Result<VM, VmError> {
+        let (header, code) = load_image(image)?;
+        let code_size = code_size.or(header.map(|header| header.code_size)).map_or(code.len(), |code_size| code_size as usize);
+        let mut memory = DefaultMemory::new_with_code_size(code, code_size)?;
+        memory.set_protection(protect);
+        let mut vm = VM::new_with_custom_memory(Box::new(memory));
+        vm.set_entry(header.map_or(0x00, |header| header.entry));
+        Ok(vm)
     }
 
     pub fn new_with_custom_memory(memory: Box<dyn Memory>) -> VM {
-        VM { sp: 0xff, csp: 0xff, pc: 0x00, reg_a: 0x00, reg_b: 0x00, memory, breakpoints: HashSet::new() }
+        VM {
+            sp: 0xff,
+            csp: 0xff,
+            pc: 0x00,
+            reg_a: 0x00,
+            reg_b: 0x00,
+            entry: 0x00,
+            memory,
+            breakpoints: HashSet::new(),
+            fuel: None,
+            cost_table: CostTable::new(),
+        }
+    }
+
+    // Creates a VM with default memory from a snapshot taken with VM::snapshot
+    pub fn from_snapshot(snapshot: &VmSnapshot) -> Result<VM, VmError> {
+        let mut memory = DefaultMemory::new_with_layout(
+            snapshot.memory.clone(),
+            snapshot.code_size,
+            snapshot.stack_start_address,
+            snapshot.call_stack_start_address,
+            snapshot.ram_start_address,
+        )?;
+        memory.set_protection(snapshot.protected);
+        let mut vm = VM::new_with_custom_memory(Box::new(memory));
+        vm.restore(snapshot)?;
+        Ok(vm)
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot {
+        let mut breakpoints: Vec<u16> = self.breakpoints.iter().copied().collect();
+        breakpoints.sort_unstable();
+
+        VmSnapshot {
+            pc: self.pc,
+            entry: self.entry,
+            sp: self.sp,
+            csp: self.csp,
+            reg_a: self.reg_a,
+            reg_b: self.reg_b,
+            fuel: self.fuel,
+            code_size: self.memory.code_size(),
+            stack_start_address: self.memory.stack_start_address(),
+            call_stack_start_address: self.memory.call_stack_start_address(),
+            ram_start_address: self.memory.ram_start_address(),
+            protected: self.memory.is_protection_enabled(),
+            breakpoints,
+            memory: self.memory.dump_memory(),
+        }
+    }
+
+    // Restores registers, entry point, stacks, breakpoints and memory. Snapshot memory layout has to match
+    // the layout of this VM's memory.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) -> Result<(), VmError> {
+        if snapshot.code_size != self.memory.code_size() ||
+            snapshot.stack_start_address != self.memory.stack_start_address() ||
+            snapshot.call_stack_start_address != self.memory.call_stack_start_address() ||
+            snapshot.ram_start_address != self.memory.ram_start_address() {
+            return Err(VmError::InvalidSnapshot(String::from("Memory layout doesn't match")));
+        }
+
+        self.memory.restore_memory(&snapshot.memory)?;
+        self.pc = snapshot.pc;
+        self.entry = snapshot.entry;
+        self.sp = snapshot.sp;
+        self.csp = snapshot.csp;
+        self.reg_a = snapshot.reg_a;
+        self.reg_b = snapshot.reg_b;
+        self.fuel = snapshot.fuel;
+        self.breakpoints = snapshot.breakpoints.iter().copied().collect();
+        Ok(())
     }
 
-    pub fn peek_memory(&self, address: u16) -> Result<u8, String> {
+    pub fn peek_memory(&self, address: u16) -> Result<u8, VmError> {
         self.memory.read_u8(address)
     }
 
@@ -77,6 +218,32 @@ impl VM {
         self.memory.as_mut()
     }
 
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn get_sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn get_csp(&self) -> u8 {
+        self.csp
+    }
+
+    // Values on the data stack, top of the stack first
+    pub fn stack_values(&self) -> Vec<u8> {
+        let start = self.memory.stack_start_address();
+        // Stack grows downwards, top is at the lowest address
+        self.memory.dump_memory_range(start + self.sp as u16 + 1, start + VM_STACK_SIZE as u16 - 1)
+    }
+
+    // Return addresses on the call stack, innermost call first
+    pub fn call_stack_addresses(&self) -> Vec<u16> {
+        let start = self.memory.call_stack_start_address();
+        let bytes = self.memory.dump_memory_range(start + self.csp as u16 + 1, start + VM_STACK_SIZE as u16 - 1);
+        bytes.chunks_exact(2).map(|bytes| VM::address_from_bytes(bytes[1], bytes[0])).collect()
+    }
+
     pub fn get_reg_a(&self) -> u8 {
         self.reg_a
     }
@@ -85,14 +252,34 @@ impl VM {
         self.reg_b
     }
 
+    pub fn set_reg_a(&mut self, value: u8) {
+        self.reg_a = value;
+    }
+
+    pub fn set_reg_b(&mut self, value: u8) {
+        self.reg_b = value;
+    }
+
     pub fn reset(&mut self) {
         self.sp = 0xff;
         self.csp = 0xff;
-        self.pc = 0x00;
+        self.pc = self.entry;
         self.reg_a = 0x00;
         self.reg_b = 0x00;
     }
 
+    pub fn set_entry(&mut self, address: u16) {
+        self.entry = address;
+    }
+
+    pub fn get_entry(&self) -> u16 {
+        self.entry
+    }
+
+    pub fn get_code_size(&self) -> u16 {
+        self.memory.code_size()
+    }
+
     pub fn set_breakpoint(&mut self, address: u16) {
         self.breakpoints.insert(address);
     }
@@ -101,51 +288,106 @@ impl VM {
         self.breakpoints.remove(&address)
     }
 
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
     pub fn clear_breakpoints(&mut self) {
         self.breakpoints.clear();
     }
 
-    pub fn execute(&mut self, interrupt_handler: fn(&mut VM, InterruptType)) -> Result<(), String> {
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, amount: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(amount));
+    }
+
+    pub fn get_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_cost_table(&mut self, cost_table: CostTable) {
+        self.cost_table = cost_table;
+    }
+
+    pub fn get_cost_table(&self) -> &CostTable {
+        &self.cost_table
+    }
+
+    pub fn execute<H: InterruptHandler + ?Sized>(&mut self, interrupt_handler: &mut H) -> Result<ExitStatus, VmError> {
         self.reset();
         self.continue_execution(interrupt_handler)
     }
 
-    pub fn continue_execution(&mut self, interrupt_handler: fn(&mut VM, InterruptType)) -> Result<(), String> {
+    // Resets the VM and starts executing at the address instead of the entry point
+    pub fn execute_from<H: InterruptHandler + ?Sized>(&mut self, address: u16, interrupt_handler: &mut H) -> Result<ExitStatus, VmError> {
+        self.reset();
+        self.pc = address;
+        self.continue_execution(interrupt_handler)
+    }
+
+    pub fn continue_execution<H: InterruptHandler + ?Sized>(&mut self, interrupt_handler: &mut H) -> Result<ExitStatus, VmError> {
         loop {
             match self.execute_instruction()? {
                 ExecutionStatus::Continue => continue,
-                ExecutionStatus::Done => return Ok(()),
+                ExecutionStatus::Done => return Ok(ExitStatus::Done),
+                ExecutionStatus::OutOfFuel => return Ok(ExitStatus::OutOfFuel),
                 ExecutionStatus::SysCall => {
-                    interrupt_handler(self, InterruptType::SysCall);
+                    interrupt_handler.handle_interrupt(self, InterruptType::SysCall)?;
                 },
                 ExecutionStatus::Breakpoint => {
-                    interrupt_handler(self, InterruptType::Breakpoint);
+                    interrupt_handler.handle_interrupt(self, InterruptType::Breakpoint)?;
+                }
+                ExecutionStatus::Trap(error) => {
+                    interrupt_handler.handle_interrupt(self, InterruptType::Trap(error))?;
+                    // A trapping instruction still stops at a breakpoint after it
+                    if self.breakpoints.contains(&self.pc) {
+                        interrupt_handler.handle_interrupt(self, InterruptType::Breakpoint)?;
+                    }
                 }
             }
         }
     }
 
-    pub fn execute_instruction(&mut self) -> Result<ExecutionStatus, String> {
-        let opcode_byte = match self.memory.read_u8(self.pc) {
-            Ok(opcode_byte) => opcode_byte,
-            Err(err) => return Err(err)
-        };
+    pub fn execute_instruction(&mut self) -> Result<ExecutionStatus, VmError> {
+        let pc = self.pc;
+        let opcode_byte = self.memory.fetch_u8(pc)?;
 
         let opcode = match Opcode::try_from(opcode_byte) {
             Ok(opcode) => opcode,
-            Err(_) => {
-                return Err(String::from(format!("Unknown opcode byte: {}", opcode_byte)));
-            }
+            Err(_) => return Err(VmError::UnknownOpcode { byte: opcode_byte, pc }),
         };
 
+        if let Some(fuel) = self.fuel {
+            let cost = self.cost_table.get_cost(opcode) as u64;
+            if fuel < cost {
+                // Leave pc on this instruction so execution can be resumed
+                return Ok(ExecutionStatus::OutOfFuel);
+            }
+            self.fuel = Some(fuel - cost);
+        }
+
         self.pc = self.pc.wrapping_add(1);
 
+        match self.execute_opcode(opcode) {
+            Ok(ExecutionStatus::Trap(error)) => {
+                Ok(ExecutionStatus::Trap(VmError::Fault { pc, opcode, error: Box::new(error) }))
+            }
+            Ok(status) => Ok(status),
+            Err(error) => Err(VmError::Fault { pc, opcode, error: Box::new(error) }),
+        }
+    }
+
+    fn execute_opcode(&mut self, opcode: Opcode) -> Result<ExecutionStatus, VmError> {
         match opcode {
             Opcode::Return => {
                 let address = match self.call_stack_pop_address() {
                     Ok(address) => address,
                     // Call stack is empty - end execution
-                    Err(_) => return Ok(ExecutionStatus::Done),
+                    Err(VmError::CallStackUnderflow) => return Ok(ExecutionStatus::Done),
+                    Err(err) => return Err(err),
                 };
                 self.pc = address;
             }
@@ -198,17 +440,13 @@ impl VM {
             }
             Opcode::Load16 => {
                 let address = self.operand_address()?;
-                let msb = self.memory.read_u8(address)?;
-                let lsb = self.memory.read_u8(address + 1)?;
-                self.stack_push(lsb)?;
-                self.stack_push(msb)?;
+                let value = self.read_u16(address)?;
+                self.stack_push_address(value)?;
             }
             Opcode::Load16C => {
                 let address = self.stack_pop_address()?;
-                let msb = self.memory.read_u8(address)?;
-                let lsb = self.memory.read_u8(address + 1)?;
-                self.stack_push(lsb)?;
-                self.stack_push(msb)?;
+                let value = self.read_u16(address)?;
+                self.stack_push_address(value)?;
             }
             Opcode::Store8 => {
                 let address = self.operand_address()?;
@@ -222,17 +460,13 @@ impl VM {
             }
             Opcode::Store16 => {
                 let address = self.operand_address()?;
-                let msb = self.stack_pop()?;
-                let lsb = self.stack_pop()?;
-                self.memory.write_u8(address, msb)?;
-                self.memory.write_u8(address + 1, lsb)?;
+                let value = self.stack_pop_address()?;
+                self.write_u16(address, value)?;
             }
             Opcode::Store16C => {
                 let address = self.stack_pop_address()?;
-                let msb = self.stack_pop()?;
-                let lsb = self.stack_pop()?;
-                self.memory.write_u8(address, msb)?;
-                self.memory.write_u8(address + 1, lsb)?;
+                let value = self.stack_pop_address()?;
+                self.write_u16(address, value)?;
             }
             Opcode::Eqz => {
                 let value = self.stack_pop()?;
@@ -336,24 +570,37 @@ impl VM {
                 let lhs = self.stack_pop()?;
                 self.stack_push(lhs.wrapping_mul(rhs))?;
             }
+            // Division by zero pushes 0 and raises a trap. Signed overflow (-128 / -1) wraps.
             Opcode::DivS => {
                 let rhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
                 let lhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
+                if rhs == 0 {
+                    return self.division_by_zero();
+                }
                 self.stack_push(u8::from_le_bytes(lhs.wrapping_div(rhs).to_le_bytes()))?;
             }
             Opcode::DivU => {
                 let rhs = self.stack_pop()?;
                 let lhs = self.stack_pop()?;
+                if rhs == 0 {
+                    return self.division_by_zero();
+                }
                 self.stack_push(lhs.wrapping_div(rhs))?;
             }
             Opcode::RemS => {
                 let rhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
                 let lhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
+                if rhs == 0 {
+                    return self.division_by_zero();
+                }
                 self.stack_push(u8::from_le_bytes(lhs.wrapping_rem(rhs).to_le_bytes()))?;
             }
             Opcode::RemU => {
                 let rhs = self.stack_pop()?;
                 let lhs = self.stack_pop()?;
+                if rhs == 0 {
+                    return self.division_by_zero();
+                }
                 self.stack_push(lhs.wrapping_rem(rhs))?;
             }
             Opcode::Pow => {
@@ -363,7 +610,8 @@ impl VM {
             }
             Opcode::Abs => {
                 let value = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
-                self.stack_push(u8::from_le_bytes(value.abs().to_le_bytes()))?;
+                // abs(-128) doesn't fit into i8 and stays -128
+                self.stack_push(u8::from_le_bytes(value.wrapping_abs().to_le_bytes()))?;
             }
             Opcode::And => {
                 let rhs = self.stack_pop()?;
@@ -380,20 +628,22 @@ impl VM {
                 let lhs = self.stack_pop()?;
                 self.stack_push(rhs ^ lhs)?;
             }
+            // Shift amount is unsigned. Shifting by 8 or more shifts out every bit.
             Opcode::Shl => {
                 let rhs = self.stack_pop()?;
                 let lhs = self.stack_pop()?;
-                self.stack_push(lhs << rhs)?;
+                self.stack_push(lhs.checked_shl(rhs as u32).unwrap_or(0))?;
             }
             Opcode::ShrS => {
-                let rhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
+                let rhs = self.stack_pop()?;
                 let lhs = i8::from_le_bytes(self.stack_pop()?.to_le_bytes());
-                self.stack_push(u8::from_le_bytes((lhs >> rhs).to_le_bytes()))?;
+                let value = lhs.checked_shr(rhs as u32).unwrap_or(if lhs < 0 { -1 } else { 0 });
+                self.stack_push(u8::from_le_bytes(value.to_le_bytes()))?;
             }
             Opcode::ShrU => {
                 let rhs = self.stack_pop()?;
                 let lhs = self.stack_pop()?;
-                self.stack_push(lhs >> rhs)?;
+                self.stack_push(lhs.checked_shr(rhs as u32).unwrap_or(0))?;
             }
             Opcode::Rotl => {
                 let rhs = self.stack_pop()?;
@@ -420,7 +670,7 @@ impl VM {
                 self.reg_b = value;
             }
             _ => {
-                return Err(String::from("Opcode has no implementation"));
+                return Err(VmError::UnimplementedOpcode(opcode));
             }
         }
 
@@ -431,133 +681,155 @@ impl VM {
         Ok(ExecutionStatus::Continue)
     }
 
+    fn division_by_zero(&mut self) -> Result<ExecutionStatus, VmError> {
+        self.stack_push(0)?;
+        Ok(ExecutionStatus::Trap(VmError::DivisionByZero))
+    }
+
     #[inline(always)]
-    pub fn stack_push(&mut self, value: u8) -> Result<(), String> {
-        if self.sp <= 0 {
-            return Err(String::from("Stack overflow"));
+    pub fn stack_push(&mut self, value: u8) -> Result<(), VmError> {
+        if self.sp == 0 {
+            return Err(VmError::StackOverflow);
         }
 
         let address = self.memory.stack_start_address().wrapping_add(self.sp as u16);
         assert!(address >= self.memory.stack_start_address());
         self.sp = self.sp.wrapping_sub(1);
-        self.memory.write_u8(address, value)?;
+        self.memory.stack_write_u8(address, value)?;
         Ok(())
     }
 
     #[inline(always)]
-    pub fn stack_pop(&mut self) -> Result<u8, String> {
-        if self.sp >= 0xff {
-            return Err(String::from("Stack is empty"));
+    pub fn stack_pop(&mut self) -> Result<u8, VmError> {
+        if self.sp == 0xff {
+            return Err(VmError::StackUnderflow);
         }
         self.sp = self.sp.wrapping_add(1);
         let address = self.memory.stack_start_address().wrapping_add(self.sp as u16);
         assert!(address >= self.memory.stack_start_address());
-        self.memory.read_u8(address)
+        self.memory.stack_read_u8(address)
     }
 
     #[inline(always)]
-    pub fn call_stack_push(&mut self, value: u8) -> Result<(), String> {
-        if self.csp <= 0 {
-            return Err(String::from("Call stack overflow"));
+    pub fn call_stack_push(&mut self, value: u8) -> Result<(), VmError> {
+        if self.csp == 0 {
+            return Err(VmError::CallStackOverflow);
         }
 
         let address = self.memory.call_stack_start_address().wrapping_add(self.csp as u16);
         assert!(address >= self.memory.call_stack_start_address());
         self.csp = self.csp.wrapping_sub(1);
-        self.memory.write_u8(address, value)?;
+        self.memory.stack_write_u8(address, value)?;
         Ok(())
     }
 
     #[inline(always)]
-    pub fn call_stack_pop(&mut self) -> Result<u8, String> {
-        if self.csp >= 0xff {
-            return Err(String::from("Call stack is empty"));
+    pub fn call_stack_pop(&mut self) -> Result<u8, VmError> {
+        if self.csp == 0xff {
+            return Err(VmError::CallStackUnderflow);
         }
         self.csp = self.csp.wrapping_add(1);
         let address = self.memory.call_stack_start_address().wrapping_add(self.csp as u16);
         assert!(address >= self.memory.call_stack_start_address());
-        self.memory.read_u8(address)
+        self.memory.stack_read_u8(address)
     }
 
     #[inline(always)]
-    pub fn stack_peek(&mut self, offset: u8) -> Result<(), String> {
+    pub fn stack_peek(&mut self, offset: u8) -> Result<(), VmError> {
         let stack_offset = self.sp as u16 + offset as u16;
         if stack_offset > 0xff {
-            return Err(String::from("Stack offset out of range"));
+            return Err(VmError::StackOffsetOutOfRange { offset });
         }
 
         let address = self.memory.stack_start_address().wrapping_add(stack_offset);
         assert!(address >= self.memory.stack_start_address());
-        let value = self.memory.read_u8(address)?;
+        let value = self.memory.stack_read_u8(address)?;
         self.stack_push(value)?;
 
         Ok(())
     }
 
     #[inline(always)]
-    pub fn stack_set(&mut self, offset: u8) -> Result<(), String> {
+    pub fn stack_set(&mut self, offset: u8) -> Result<(), VmError> {
         let stack_offset = self.sp as u16 + offset as u16;
         if stack_offset > 0xff {
-            return Err(String::from("Stack offset out of range"));
+            return Err(VmError::StackOffsetOutOfRange { offset });
         }
 
         let value = self.stack_pop()?;
         let address = self.memory.stack_start_address().wrapping_add(stack_offset);
         assert!(address >= self.memory.stack_start_address());
-        self.memory.write_u8(address, value)?;
+        self.memory.stack_write_u8(address, value)?;
 
         Ok(())
     }
 
     #[inline(always)]
     fn address_from_bytes(msb: u8, lsb: u8) -> u16 {
-        ((msb as u16) << 8) | lsb as u16
+        u16::from_le_bytes([lsb, msb])
     }
 
     #[inline(always)]
-    fn operand_address(&mut self) -> Result<u16, String> {
+    fn operand_address(&mut self) -> Result<u16, VmError> {
         let address = VM::address_from_bytes(
-            self.memory.read_u8(self.pc.wrapping_add(1))?,
-            self.memory.read_u8(self.pc)?
+            self.memory.fetch_u8(self.pc.wrapping_add(1))?,
+            self.memory.fetch_u8(self.pc)?
         );
         self.pc = self.pc.wrapping_add(2);
 
         Ok(address)
     }
 
+    // 16-bit memory accesses wrap around at the top of the address space
+    #[inline(always)]
+    fn read_u16(&self, address: u16) -> Result<u16, VmError> {
+        let lsb = self.memory.read_u8(address)?;
+        let msb = self.memory.read_u8(address.wrapping_add(1))?;
+        Ok(VM::address_from_bytes(msb, lsb))
+    }
+
     #[inline(always)]
-    fn operand_value(&mut self) -> Result<u8, String> {
-        let value = self.memory.read_u8(self.pc)?;
+    fn write_u16(&mut self, address: u16, value: u16) -> Result<(), VmError> {
+        let [lsb, msb] = value.to_le_bytes();
+        self.memory.write_u8(address, lsb)?;
+        self.memory.write_u8(address.wrapping_add(1), msb)?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn operand_value(&mut self) -> Result<u8, VmError> {
+        let value = self.memory.fetch_u8(self.pc)?;
         self.pc = self.pc.wrapping_add(1);
 
         Ok(value)
     }
 
+    // Pushes msb first so lsb ends up on top of the stack at the lower address
     #[inline(always)]
-    pub fn stack_push_address(&mut self, address: u16) -> Result<(), String> {
-        self.stack_push((address & 0x00ff) as u8)?;
+    pub fn stack_push_address(&mut self, address: u16) -> Result<(), VmError> {
         self.stack_push((address >> 8) as u8)?;
+        self.stack_push((address & 0x00ff) as u8)?;
         Ok(())
     }
 
     #[inline(always)]
-    pub fn stack_pop_address(&mut self) -> Result<u16, String> {
-        let msb = self.stack_pop()?;
+    pub fn stack_pop_address(&mut self) -> Result<u16, VmError> {
         let lsb = self.stack_pop()?;
+        let msb = self.stack_pop()?;
         Ok(VM::address_from_bytes(msb, lsb))
     }
 
     #[inline(always)]
-    pub fn call_stack_push_address(&mut self, address: u16) -> Result<(), String> {
-        self.call_stack_push((address & 0x00ff) as u8)?;
+    pub fn call_stack_push_address(&mut self, address: u16) -> Result<(), VmError> {
         self.call_stack_push((address >> 8) as u8)?;
+        self.call_stack_push((address & 0x00ff) as u8)?;
         Ok(())
     }
 
     #[inline(always)]
-    pub fn call_stack_pop_address(&mut self) -> Result<u16, String> {
-        let msb = self.call_stack_pop()?;
+    pub fn call_stack_pop_address(&mut self) -> Result<u16, VmError> {
         let lsb = self.call_stack_pop()?;
+        let msb = self.call_stack_pop()?;
         Ok(VM::address_from_bytes(msb, lsb))
     }
 
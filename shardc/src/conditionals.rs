@@ -0,0 +1,193 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::collections::HashMap;
+
+use shard_compiler::diagnostic::{Diagnostic, SourceLine};
+use shard_compiler::expression::Expression;
+use shard_compiler::lexer;
+
+// Names defined with #define or -D. Value is None when it can't be evaluated
+// by the preprocessor, e.g. when it refers to .equ constants.
+pub type Defines = HashMap<String, Option<i64>>;
+
+struct Branch {
+    line: SourceLine,
+    keyword: String,
+    // Whether lines around the whole #if block are assembled
+    parent_active: bool,
+    // Whether one of the branches was already taken
+    taken: bool,
+    active: bool,
+    has_else: bool,
+}
+
+// Evaluates #if, #ifdef, #ifndef, #elif, #else and #endif directives. Directives and lines of
+// inactive branches are blanked so line numbers stay the same. #define lines are kept for the assembler.
+pub fn apply_conditionals(lines: &mut [SourceLine], defines: &mut Defines) -> Result<(), Diagnostic> {
+    let mut branches: Vec<Branch> = vec![];
+
+    for line in lines.iter_mut() {
+        let tokens = match lexer::tokenize_with_columns(&line.text) {
+            Ok(tokens) => tokens,
+            Err(err) => return Err(line_error(line, &err))
+        };
+        let (keyword_column, keyword) = match tokens.first() {
+            Some((column, keyword)) => (*column, keyword.as_str()),
+            None => continue
+        };
+        let keyword_error = |message: String| Diagnostic::at(&message, line, keyword_column, keyword.len());
+        let args = tokens[1..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" ");
+        let active = branches.last().is_none_or(|branch| branch.active);
+
+        match keyword {
+            "#if" | "#ifdef" | "#ifndef" => {
+                let condition = active && match keyword {
+                    "#if" => evaluate(&args, defines, line)?,
+                    "#ifdef" => defines.contains_key(define_name(&tokens, line)?),
+                    _ => !defines.contains_key(define_name(&tokens, line)?),
+                };
+                branches.push(Branch {
+                    line: line.clone(),
+                    keyword: keyword.to_string(),
+                    parent_active: active,
+                    taken: condition,
+                    active: condition,
+                    has_else: false,
+                });
+            }
+            "#elif" | "#else" => {
+                let branch = match branches.last_mut() {
+                    Some(branch) => branch,
+                    None => return Err(keyword_error(format!("{} without #if", keyword)))
+                };
+                if branch.has_else {
+                    return Err(keyword_error(format!("{} after #else of {} at {}", keyword, branch.keyword, branch.line.location)));
+                }
+
+                let condition = branch.parent_active && !branch.taken && match keyword {
+                    "#elif" => evaluate(&args, defines, line)?,
+                    _ => true,
+                };
+                branch.active = condition;
+                branch.taken |= condition;
+                branch.has_else = keyword == "#else";
+            }
+            "#endif" => {
+                if branches.pop().is_none() {
+                    return Err(keyword_error(String::from("#endif without #if")));
+                }
+            }
+            _ if !active => {}
+            "#define" => {
+                let name = define_name(&tokens, line)?.to_string();
+                let value = match args.split_once(' ') {
+                    Some((_, value)) => Expression::parse(value).and_then(|expression| {
+                        expression.evaluate(&|name| defines.get(name).copied().flatten())
+                    }).ok(),
+                    None => Some(1),
+                };
+                // Command line defines take precedence, the assembler reports other redefinitions
+                defines.entry(name).or_insert(value);
+                // Assembler needs the constant too
+                continue;
+            }
+            _ => continue,
+        }
+
+        line.text.clear();
+    }
+
+    match branches.last() {
+        Some(branch) => {
+            let column = branch.line.full_span().column;
+            Err(Diagnostic::at(&format!("{} is missing #endif", branch.keyword), &branch.line, column, branch.keyword.len()))
+        }
+        None => Ok(())
+    }
+}
+
+fn line_error(line: &SourceLine, message: &str) -> Diagnostic {
+    let span = line.full_span();
+    Diagnostic::at(message, line, span.column, span.length)
+}
+
+fn define_name<'a>(tokens: &'a [(usize, String)], line: &SourceLine) -> Result<&'a str, Diagnostic> {
+    match tokens.get(1) {
+        Some((_, name)) => Ok(name),
+        None => Err(Diagnostic::at(&format!("{} is missing a name", tokens[0].1), line, tokens[0].0, tokens[0].1.len()))
+    }
+}
+
+// Names that aren't defined evaluate to 0
+fn evaluate(source: &str, defines: &Defines, line: &SourceLine) -> Result<bool, Diagnostic> {
+    let expression = match replace_defined(source, defines).and_then(|source| Expression::parse(&source)) {
+        Ok(expression) => expression,
+        Err(err) => return Err(line_error(line, &format!("invalid condition '{}' - {}", source, err)))
+    };
+    for name in expression.symbols() {
+        if let Some(None) = defines.get(name) {
+            return Err(line_error(line, &format!("value of '{}' is not known to the preprocessor", name)));
+        }
+    }
+
+    match expression.evaluate(&|name| Some(defines.get(name).copied().flatten().unwrap_or(0))) {
+        Ok(value) => Ok(value != 0),
+        Err(err) => Err(line_error(line, &format!("invalid condition '{}' - {}", source, err)))
+    }
+}
+
+// Replaces defined(NAME) and defined NAME with 1 or 0, the expression parser only knows values
+fn replace_defined(source: &str, defines: &Defines) -> Result<String, String> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    let mut result = String::new();
+    let mut rest = source;
+
+    while let Some(position) = rest.find("defined") {
+        let before = &rest[..position];
+        let after = &rest[position + "defined".len()..];
+        result.push_str(before);
+        // Part of a longer name, e.g. UNDEFINED
+        if before.ends_with(is_name_char) || after.starts_with(is_name_char) {
+            result.push_str("defined");
+            rest = after;
+            continue;
+        }
+
+        let (inner, parenthesized) = match after.trim_start().strip_prefix('(') {
+            Some(inner) => (inner.trim_start(), true),
+            None => (after.trim_start(), false),
+        };
+        let name_length = inner.find(|c: char| !is_name_char(c)).unwrap_or(inner.len());
+        if name_length == 0 {
+            return Err(String::from("defined is missing a name"));
+        }
+        rest = &inner[name_length..];
+        if parenthesized {
+            rest = match rest.trim_start().strip_prefix(')') {
+                Some(rest) => rest,
+                None => return Err(String::from("defined is missing ')'")),
+            };
+        }
+        result.push_str(if defines.contains_key(&inner[..name_length]) { "1" } else { "0" });
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
@@ -0,0 +1,273 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::collections::HashMap;
+
+use shard_compiler::diagnostic::{Diagnostic, SourceLine};
+use shard_compiler::lexer;
+use shard_core::opcodes::Opcode;
+
+// #macro name param_a, param_b
+//     push %param_a
+// %%local_label:
+// #endmacro
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    definition: SourceLine,
+}
+
+// Collects macro definitions from the whole program and expands their invocations.
+// Expanded lines point at the macro body and have a note about the invocation.
+pub fn expand_macros(lines: &[SourceLine]) -> Result<Vec<SourceLine>, Diagnostic> {
+    let mut macros = HashMap::new();
+    let mut source = vec![];
+
+    let mut line_it = lines.iter();
+    while let Some(line) = line_it.next() {
+        let tokens = tokenize(line)?;
+        match tokens.first().map(|(_, token)| token.as_str()) {
+            Some("#macro") => {
+                let (name, params) = parse_signature(&tokens, line)?;
+                let mut body = vec![];
+                loop {
+                    match line_it.next() {
+                        Some(body_line) if first_token(&body_line.text) == Some("#endmacro") => break,
+                        Some(body_line) if first_token(&body_line.text) == Some("#macro") => {
+                            return Err(line_error(line, &format!("macro '{}' is not closed before next #macro", name)))
+                        }
+                        Some(body_line) => body.push(body_line.clone()),
+                        None => return Err(line_error(line, &format!("macro '{}' is missing #endmacro", name))),
+                    }
+                }
+
+                if let Some(existing) = macros.get(&name) {
+                    let existing: &Macro = existing;
+                    let message = format!("macro '{}' is already defined at {}", name, existing.definition.location);
+                    return Err(line_error(line, &message));
+                }
+                macros.insert(name, Macro { params, body, definition: line.clone() });
+            }
+            Some("#endmacro") => return Err(line_error(line, "#endmacro without #macro")),
+            _ => source.push(line),
+        }
+    }
+
+    let mut expander = Expander { macros: &macros, expansion_count: 0, active: vec![] };
+    let mut expanded = vec![];
+    for line in source {
+        expander.expand_line(line.clone(), &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    // Used to make local labels unique
+    expansion_count: usize,
+    // Macros being expanded right now
+    active: Vec<&'a str>,
+}
+
+impl<'a> Expander<'a> {
+    fn expand_line(&mut self, line: SourceLine, output: &mut Vec<SourceLine>) -> Result<(), Diagnostic> {
+        let tokens = tokenize(&line)?;
+        let (name, macro_definition) = match tokens.first().and_then(|(_, name)| self.macros.get_key_value(name)) {
+            Some(found) => found,
+            None => {
+                output.push(line);
+                return Ok(());
+            }
+        };
+
+        let invocation_error = |message: String| {
+            let mut diagnostic = Diagnostic::at(&message, &line, tokens[0].0, name.len());
+            diagnostic.add_note(&format!("macro '{}' is defined at {}", name, macro_definition.definition.location));
+            diagnostic
+        };
+        let args = split_args(&tokens[1..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" "));
+        if args.len() != macro_definition.params.len() {
+            return Err(invocation_error(format!(
+                "macro '{}' expects {} argument(s), got {}", name, macro_definition.params.len(), args.len()
+            )));
+        }
+        if self.active.contains(&name.as_str()) {
+            return Err(invocation_error(format!("macro '{}' is expanded recursively", name)));
+        }
+
+        self.expansion_count += 1;
+        let expansion_id = self.expansion_count;
+
+        // Innermost expansion is noted first
+        let mut notes = vec![format!("in expansion of macro '{}' at {}", name, line.location)];
+        notes.extend(line.notes.iter().cloned());
+
+        self.active.push(name);
+        for body_line in macro_definition.body.iter() {
+            let mut expanded_line = body_line.clone();
+            expanded_line.location.expansion = expansion_id;
+            expanded_line.notes = notes.clone();
+            expanded_line.text = substitute(&body_line.text, name, expansion_id, &macro_definition.params, &args)
+                .map_err(|err| line_error(&expanded_line, &err))?;
+            self.expand_line(expanded_line, output)?;
+        }
+        self.active.pop();
+        Ok(())
+    }
+}
+
+fn tokenize(line: &SourceLine) -> Result<Vec<(usize, String)>, Diagnostic> {
+    lexer::tokenize_with_columns(&line.text).map_err(|err| line_error(line, &err))
+}
+
+fn line_error(line: &SourceLine, message: &str) -> Diagnostic {
+    let span = line.full_span();
+    Diagnostic::at(message, line, span.column, span.length)
+}
+
+fn first_token(line: &str) -> Option<&str> {
+    line.split_whitespace().next()
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_signature(tokens: &[(usize, String)], line: &SourceLine) -> Result<(String, Vec<String>), Diagnostic> {
+    let name = match tokens.get(1) {
+        Some((_, name)) => name.clone(),
+        None => return Err(line_error(line, "#macro is missing a name")),
+    };
+    if !is_name(&name) || Opcode::from_string(&name).is_some() {
+        return Err(Diagnostic::at(&format!("invalid macro name '{}'", name), line, tokens[1].0, name.len()));
+    }
+
+    let params = split_args(&tokens[2..].iter().map(|(_, token)| token.as_str()).collect::<Vec<&str>>().join(" "));
+    for (index, param) in params.iter().enumerate() {
+        if !is_name(param) {
+            return Err(line_error(line, &format!("invalid parameter '{}' of macro '{}'", param, name)));
+        }
+        if params[..index].contains(param) {
+            return Err(line_error(line, &format!("duplicate parameter '{}' of macro '{}'", param, name)));
+        }
+    }
+    Ok((name, params))
+}
+
+// Splits on commas outside of quotes and parentheses
+fn split_args(args: &str) -> Vec<String> {
+    if args.trim().is_empty() {
+        return vec![];
+    }
+
+    let mut result = vec![];
+    let mut arg = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0;
+    for c in args.chars() {
+        match quote {
+            Some(quote_char) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote_char {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ',' if depth == 0 => {
+                    result.push(arg.trim().to_string());
+                    arg.clear();
+                    continue;
+                }
+                _ => {}
+            },
+        }
+        arg.push(c);
+    }
+    result.push(arg.trim().to_string());
+    result
+}
+
+// Replaces %param with its argument and %%label with a label unique to this expansion. Label
+// starts with a dot so that it stays local to the object file when the module is compiled separately.
+// Strings, characters and comments are left as they are.
+fn substitute(line: &str, macro_name: &str, expansion_id: usize, params: &[String], args: &[String]) -> Result<String, String> {
+    let mut result = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut char_it = line.chars().peekable();
+    while let Some(c) = char_it.next() {
+        if let Some(quote_char) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote_char {
+                quote = None;
+            }
+            result.push(c);
+            continue;
+        }
+
+        match c {
+            '%' => {}
+            ';' => {
+                result.push(c);
+                result.extend(char_it.by_ref());
+                break;
+            }
+            _ => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                result.push(c);
+                continue;
+            }
+        }
+
+        let local = char_it.peek() == Some(&'%');
+        if local {
+            char_it.next();
+        }
+        let mut name = String::new();
+        while let Some(c) = char_it.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+            name.push(*c);
+            char_it.next();
+        }
+
+        if local {
+            if name.is_empty() {
+                return Err(String::from("'%%' is missing a label name"));
+            }
+            result.push_str(&format!(".{}_{}_{}", macro_name, expansion_id, name));
+        } else {
+            match params.iter().position(|param| *param == name) {
+                Some(index) => result.push_str(&args[index]),
+                None => return Err(format!("unknown macro parameter '%{}'", name)),
+            }
+        }
+    }
+    Ok(result)
+}
@@ -17,6 +17,9 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+mod conditionals;
+mod macros;
+
 #[cfg(test)]
 mod tests;
 
@@ -26,66 +29,350 @@ use std::io::{BufRead, Write};
 use std::path::Path;
 use std::collections::{HashSet, HashMap};
 
+use shard_core::image;
+use shard_compiler::diagnostic::{Diagnostic, SourceLine};
+use shard_compiler::object::ObjectFile;
+use shard_compiler::symbols::SymbolMap;
+
+use crate::conditionals::Defines;
+
 
 fn print_help() {
-    println!("shardc [source_file]");
+    println!("shardc [options] [source_file]");
+    println!("shardc -c [options] <source_file>");
+    println!("shardc --link <object_file>... [--symbols <symbol_file>]");
+    println!("shardc --disasm <image> [--symbols <symbol_file>]");
     println!("shardc --help");
+    println!("Options:");
+    println!("  -D <name>[=<value>]        define constant for #if and the assembler, value defaults to 1, overrides #define of the name");
+    println!("  -c                         compile the module into an object file, imported modules are linked separately");
+    println!("  --symbols <symbol_file>    write addresses of labels and globals and source lines of instructions, or read them with --disasm");
+    println!("  --entry <label>            start execution at the label instead of 0x0000");
+    println!("  --ram <bytes>              ram the program needs, checked by the VM before running it");
+    println!("  --raw                      write the image without a header, for runtimes that don't support it");
+}
+
+// NAME=VALUE or NAME
+fn parse_define(define: &str) -> Result<(String, i64), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => match shard_compiler::lexer::parse_value(value) {
+            Ok(value) => (name, value),
+            Err(err) => return Err(format!("Invalid value of define '{}' - {}", name, err))
+        },
+        None => (define, 1),
+    };
+    if name.is_empty() {
+        return Err(format!("Invalid define '{}'", define));
+    }
+    Ok((name.to_string(), value))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Compile,
+    // Module is compiled into an object file, imported modules only provide constants and macros
+    CompileObject,
+    Link,
+    Disasm,
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        print_help();
-        return;
+
+    let mut inputs = vec![];
+    let mut command_line_defines = vec![];
+    let mut mode = Mode::Compile;
+    let mut symbol_file = None;
+    let mut raw = false;
+    let mut entry = None;
+    let mut required_ram = 0;
+
+    let mut arg_it = args.iter().skip(1);
+    while let Some(arg) = arg_it.next() {
+        if arg.starts_with("-D") {
+            let define = match arg.strip_prefix("-D").filter(|define| !define.is_empty()) {
+                Some(define) => Some(define),
+                None => arg_it.next().map(|define| define.as_str()),
+            };
+            match define.map(parse_define) {
+                // Last -D of a name wins
+                Some(Ok((name, value))) => {
+                    command_line_defines.retain(|(defined, _)| *defined != name);
+                    command_line_defines.push((name, value));
+                }
+                Some(Err(err)) => {
+                    println!("{}", err);
+                    return;
+                }
+                None => {
+                    println!("-D expects a name");
+                    return;
+                }
+            }
+        } else if arg == "-c" {
+            mode = Mode::CompileObject;
+        } else if arg == "--link" {
+            mode = Mode::Link;
+        } else if arg == "--disasm" {
+            mode = Mode::Disasm;
+        } else if arg == "--symbols" {
+            match arg_it.next() {
+                Some(file) => symbol_file = Some(file),
+                None => {
+                    println!("--symbols expects a file");
+                    return;
+                }
+            }
+        } else if arg == "--ram" {
+            match arg_it.next().map(|value| value.parse::<u16>()) {
+                Some(Ok(value)) => required_ram = value,
+                _ => {
+                    println!("--ram expects a number of bytes");
+                    return;
+                }
+            }
+        } else if arg == "--entry" {
+            match arg_it.next() {
+                Some(label) => entry = Some(label),
+                None => {
+                    println!("--entry expects a label");
+                    return;
+                }
+            }
+        } else if arg == "--raw" {
+            raw = true;
+        } else if arg.starts_with('-') {
+            print_help();
+            return;
+        } else {
+            inputs.push(arg);
+        }
     }
 
-    if args[1] == "--help" {
-        print_help();
-        return;
-    } else if args[1].starts_with("-") {
+    // Only the linker takes several inputs
+    if inputs.is_empty() || (mode != Mode::Link && inputs.len() > 1) {
         print_help();
         return;
     }
 
+    match mode {
+        Mode::Disasm => {
+            if let Err(err) = disassemble_file(inputs[0], symbol_file) {
+                println!("{}", err);
+            }
+        }
+        Mode::Link => {
+            if let Some((bin, mut symbols)) = link_files(&inputs) {
+                if let Err(err) = set_linked_entry(&mut symbols, entry, raw) {
+                    println!("{}", err);
+                    return;
+                }
+                write_image(&bin, &symbols, symbol_file, raw, required_ram);
+            }
+        }
+        Mode::Compile => {
+            let lines = match load_program(inputs[0], &command_line_defines, entry, false) {
+                Some(lines) => lines,
+                None => return,
+            };
+            // Raw image has no header for the entry point and starts with a jump to it
+            let result = shard_compiler::compile_with_defines(&lines, &command_line_defines, raw);
+            match result {
+                Ok((bin, symbols)) => write_image(&bin, &symbols, symbol_file, raw, required_ram),
+                Err(diagnostics) => print_diagnostics(&diagnostics),
+            }
+        }
+        Mode::CompileObject => {
+            let lines = match load_program(inputs[0], &command_line_defines, entry, true) {
+                Some(lines) => lines,
+                None => return,
+            };
+            match shard_compiler::compile_object_with_defines(&lines, &command_line_defines) {
+                Ok(object) => {
+                    // module.srd -> module.sho
+                    let object_file = Path::new(inputs[0]).with_extension("sho");
+                    let object_file = object_file.file_name().unwrap();
+                    if std::fs::write(object_file, object.to_bytes()).is_err() {
+                        println!("Failed to write {}", object_file.to_string_lossy());
+                    }
+                }
+                Err(diagnostics) => print_diagnostics(&diagnostics),
+            }
+        }
+    }
+}
+
+// Loads the main module with its imports and expands macros. Errors are printed.
+fn load_program(source_file: &str, command_line_defines: &[(String, i64)], entry: Option<&String>, module_only: bool) -> Option<Vec<SourceLine>> {
     let main_module_name = String::from("main");
     let mut included_modules = HashSet::new();
     let mut standard_modules = HashMap::new();
     standard_modules.insert(String::from("std/malloc"), String::from(include_str!("../../standard_modules/std/malloc.srd")));
+    standard_modules.insert(String::from("std/io"), String::from(include_str!("../../standard_modules/std/io.srd")));
 
-    let lines = match load_module_from_file(&args[1], &main_module_name, &mut included_modules, &standard_modules) {
+    let mut defines = Defines::new();
+    for (name, value) in command_line_defines.iter() {
+        defines.insert(name.clone(), Some(*value));
+    }
+
+    let mut lines = match load_module_from_file(source_file, &main_module_name, &mut included_modules, &standard_modules, &mut defines) {
         Ok(lines) => lines,
-        Err(err) => {
-            println!("{}", err);
-            return;
+        Err(diagnostic) => {
+            println!("{}", diagnostic);
+            return None;
         }
     };
+    if module_only {
+        lines = module_interface(lines, source_file);
+    }
 
-    let bin = match shard_compiler::compile_from_asm(lines) {
-        Ok(bin) => bin,
-        Err(err) => {
-            println!("{}", err);
-            return;
+    if let Some(entry) = entry {
+        lines.push(SourceLine::new(&format!(".entry {}", entry), "<command line>", 1));
+    }
+
+    match macros::expand_macros(&lines) {
+        Ok(lines) => Some(lines),
+        Err(diagnostic) => {
+            println!("{}", diagnostic);
+            None
+        }
+    }
+}
+
+// Keeps lines of the main module and only constants and macros of imported modules,
+// their code is linked from their own object files
+fn module_interface(lines: Vec<SourceLine>, source_file: &str) -> Vec<SourceLine> {
+    let mut in_macro = false;
+    lines.into_iter().filter(|line| {
+        if *line.location.file == *source_file {
+            return true;
+        }
+        let keyword = line.text.split_whitespace().next();
+        match keyword {
+            Some("#macro") => in_macro = true,
+            Some("#endmacro") => {
+                in_macro = false;
+                return true;
+            }
+            _ => {}
         }
+        in_macro || keyword == Some(".equ") || keyword == Some("#define")
+    }).collect()
+}
+
+fn print_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics.iter() {
+        println!("{}\n", diagnostic);
+    }
+    println!("{} error(s) found", diagnostics.len());
+}
+
+fn write_image(bin: &[u8], symbols: &SymbolMap, symbol_file: Option<&String>, raw: bool, required_ram: u16) {
+    let image = match raw {
+        true => bin.to_vec(),
+        false => match image::write_image(bin, symbols.code_size, symbols.entry.unwrap_or(0x00), required_ram) {
+            Ok(image) => image,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        },
     };
 
     if Path::new("out.bin").exists() {
         std::fs::remove_file("out.bin").unwrap();
     }
-
     let mut out_bin = File::create("out.bin").unwrap();
-    match out_bin.write_all(bin.as_slice()) {
+    match out_bin.write_all(&image) {
         Ok(_) => {}
         Err(err) => {
             println!("{}", err);
         }
     };
+
+    if let Some(symbol_file) = symbol_file {
+        if std::fs::write(symbol_file, symbols.to_text()).is_err() {
+            println!("Failed to write {}", symbol_file);
+        }
+    }
+}
+
+fn link_files(object_files: &[&String]) -> Option<(Vec<u8>, SymbolMap)> {
+    let mut objects = vec![];
+    for object_file in object_files.iter() {
+        let object = match std::fs::read(object_file) {
+            Ok(bytes) => ObjectFile::from_bytes(&bytes).map_err(|err| format!("{}: {}", object_file, err)),
+            Err(_) => Err(format!("Failed to read {}", object_file)),
+        };
+        match object {
+            Ok(object) => objects.push((object_file.to_string(), object)),
+            Err(err) => {
+                println!("{}", err);
+                return None;
+            }
+        }
+    }
+
+    match shard_compiler::linker::link(&objects) {
+        Ok(result) => Some(result),
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics);
+            None
+        }
+    }
+}
+
+// Entry point from the command line. Linked code can't be moved to make room for a jump stub,
+// so a raw image has to start at its entry point.
+fn set_linked_entry(symbols: &mut SymbolMap, entry: Option<&String>, raw: bool) -> Result<(), String> {
+    if let Some(entry) = entry {
+        if symbols.entry.is_some() {
+            return Err(String::from("entry point is already set by the linked objects"));
+        }
+        match symbols.get_address(entry) {
+            Some(address) if address < symbols.code_size => symbols.entry = Some(address),
+            Some(_) => return Err(format!("entry point '{}' is not in the code", entry)),
+            None => return Err(format!("undefined entry point '{}'", entry)),
+        }
+    }
+
+    match symbols.entry {
+        Some(address) if raw && address != 0x00 => Err(format!("raw image has to start at its entry point, '{}' is at 0x{:04x}", symbols.name_at(address).unwrap_or_default(), address)),
+        _ => Ok(()),
+    }
+}
+
+fn disassemble_file(image_file: &str, symbol_file: Option<&String>) -> Result<(), String> {
+    let image = match std::fs::read(image_file) {
+        Ok(image) => image,
+        Err(_) => return Err(format!("Failed to read {}", image_file))
+    };
+    // Header tells where the code ends when there is no symbol file
+    let (image, code_size) = match image::has_header(&image) {
+        true => {
+            let (header, payload) = image::read_image(&image).map_err(|err| format!("{}: {}", image_file, err))?;
+            (payload.to_vec(), Some(header.code_size))
+        }
+        false => (image, None),
+    };
+    let symbols = match symbol_file {
+        Some(symbol_file) => match std::fs::read_to_string(symbol_file) {
+            Ok(text) => Some(SymbolMap::from_text(&text).map_err(|err| format!("{}:{}", symbol_file, err))?),
+            Err(_) => return Err(format!("Failed to read {}", symbol_file))
+        },
+        None => code_size.map(SymbolMap::new),
+    };
+
+    let listing = shard_compiler::disassembler::disassemble(&image, symbols.as_ref()).map_err(|err| format!("{}: {}", image_file, err))?;
+    print!("{}", listing);
+    Ok(())
 }
 
-fn load_source_from_file(module_path: &String) -> Result<Vec<String>, String> {
+fn load_source_from_file(module_path: &str) -> Result<Vec<String>, String> {
     let source_file = match File::open(module_path) {
         Ok(file) => file,
         Err(_) => {
-            return Err(String::from(format!("Failed to read {}", module_path)));
+            return Err(format!("Failed to read {}", module_path));
         }
     };
     let reader = io::BufReader::new(source_file);
@@ -94,7 +381,7 @@ fn load_source_from_file(module_path: &String) -> Result<Vec<String>, String> {
     for line in reader.lines() {
         match line {
             Ok(line) => lines.push(line),
-            Err(_) => { }
+            Err(_) => continue
         };
     }
 
@@ -113,87 +400,89 @@ fn load_source_from_string(module_string: &str) -> Result<Vec<String>, String> {
     Ok(lines)
 }
 
-pub fn load_module_from_file(module_path: &String, module_name: &String, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>) -> Result<Vec<String>, String> {
+pub fn load_module_from_file(module_path: &str, module_name: &String, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>, defines: &mut Defines) -> Result<Vec<SourceLine>, Diagnostic> {
     if included_modules.contains(module_name) {
         return Ok(vec![])
     }
     included_modules.insert(module_name.clone());
 
-    let mut lines = load_source_from_file(module_path)?;
+    let lines = load_source_from_file(module_path).map_err(|err| Diagnostic::new(&err))?;
+    let mut lines = SourceLine::from_lines(&lines, module_path);
     let current_module_dir = String::from(Path::new(module_path).parent().expect("Unexpected error occurred").to_str().unwrap());
 
-    preprocess_source(&mut lines, &current_module_dir, included_modules, standard_modules)?;
+    preprocess_source(&mut lines, &current_module_dir, included_modules, standard_modules, defines)?;
 
     Ok(lines)
 }
 
-pub fn load_module_from_string(module_string: &String, module_name: &String, current_module_dir: &String, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>) -> Result<Vec<String>, String> {
+pub fn load_module_from_string(module_string: &str, module_name: &String, current_module_dir: &str, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>, defines: &mut Defines) -> Result<Vec<SourceLine>, Diagnostic> {
     if included_modules.contains(module_name) {
         return Ok(vec![])
     }
     included_modules.insert(module_name.clone());
 
-    let mut lines = load_source_from_string(module_string)?;
+    let lines = load_source_from_string(module_string).map_err(|err| Diagnostic::new(&err))?;
+    let mut lines = SourceLine::from_lines(&lines, module_name);
 
-    preprocess_source(&mut lines, current_module_dir, included_modules, standard_modules)?;
+    preprocess_source(&mut lines, current_module_dir, included_modules, standard_modules, defines)?;
 
     Ok(lines)
 }
 
-fn preprocess_source(asm_source: &mut Vec<String>, current_module_dir: &String, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>) -> Result<(), String> {
-    let mut sources_to_add = vec![];
-
-    let mut lines_to_remove = vec![];
+fn preprocess_source(asm_source: &mut Vec<SourceLine>, current_module_dir: &str, included_modules: &mut HashSet<String>, standard_modules: &HashMap<String, String>, defines: &mut Defines) -> Result<(), Diagnostic> {
+    conditionals::apply_conditionals(asm_source, defines)?;
 
-    for line_number in 0..asm_source.len() {
-        let mut line = asm_source[line_number].clone();
+    let mut sources_to_add = vec![];
 
-        line = match line.find(";") {
-            None => line,
-            Some(delimiter) => String::from(line.split_at(delimiter).0)
+    for line in asm_source.iter_mut() {
+        let tokens = match shard_compiler::lexer::tokenize_with_columns(&line.text) {
+            Ok(tokens) => tokens,
+            // Reported by the assembler
+            Err(_) => continue
         };
 
-        let mut token_it = line.split_whitespace();
-
-        let keyword = match token_it.next() {
+        let (keyword_column, keyword) = match tokens.first() {
             Some(keyword) => keyword,
             None => continue
         };
 
         if keyword == "#import" {
-            let module_name = match token_it.next() {
-                Some(module) => String::from(module),
-                None => return Err(String::from(format!("{}: invalid import - module is missing", line_number + 1)))
+            let (module_column, module_name) = match tokens.get(1) {
+                Some((column, module)) => (*column, module.clone()),
+                None => return Err(Diagnostic::at("invalid import - module is missing", line, *keyword_column, keyword.len()))
             };
 
-            match standard_modules.get(&module_name) {
+            let module_source = match standard_modules.get(&module_name) {
                 None => {
-                    let mut full_module_path = current_module_dir.clone();
-                    full_module_path.push_str("/");
+                    let mut full_module_path = String::from(current_module_dir);
+                    full_module_path.push('/');
                     full_module_path.push_str(&module_name);
 
-                    sources_to_add.push(load_module_from_file(&full_module_path, &module_name, included_modules, standard_modules)?);
+                    load_module_from_file(&full_module_path, &module_name, included_modules, standard_modules, defines)
                 }
                 Some(sys_module_source) => {
                     let mock_sys_dir = String::from("");
-                    sources_to_add.push(load_module_from_string(sys_module_source, &module_name, &mock_sys_dir, included_modules, standard_modules)?);
+                    load_module_from_string(sys_module_source, &module_name, &mock_sys_dir, included_modules, standard_modules, defines)
                 }
-            }
-
-            lines_to_remove.insert(0, line_number);
+            };
+            // Errors without a location are about the module itself, point at the import
+            let module_source = module_source.map_err(|diagnostic| match diagnostic.span {
+                None => Diagnostic::at(&diagnostic.message, line, module_column, module_name.chars().count()),
+                Some(_) => diagnostic,
+            })?;
+            sources_to_add.push(module_source);
+
+            line.text.clear();
         }
     }
 
-    for line_number in lines_to_remove {
-        asm_source[line_number] = String::from("");
-    }
-
     for source_to_add in sources_to_add {
+        // Every module starts in the code section
+        if let Some(first_line) = source_to_add.first() {
+            asm_source.push(SourceLine::new(".code", &first_line.location.file, 0));
+        }
         asm_source.extend_from_slice(source_to_add.as_slice());
     }
 
     Ok(())
 }
-
-
-
@@ -18,6 +18,8 @@
 //
 
 use std::collections::{HashSet, HashMap};
+use shard_compiler::diagnostic::SourceLine;
+use crate::conditionals::Defines;
 
 #[test]
 fn test_module_import() {
@@ -33,11 +35,11 @@ fn test_module_import() {
         let mut standard_modules = HashMap::new();
         standard_modules.insert(String::from("std/malloc"), String::from(include_str!("../../standard_modules/std/malloc.srd")));
 
-        let lines = crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules).unwrap();
+        let lines = crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules, &mut Defines::new()).unwrap();
 
         let mut count = 0;
         for line in lines {
-            if line.contains("malloc:") {
+            if line.text.contains("malloc:") {
                 count += 1;
             }
         }
@@ -53,13 +55,273 @@ fn test_module_import() {
         let mut standard_modules = HashMap::new();
         standard_modules.insert(String::from("std/malloc"), String::from(include_str!("../../standard_modules/std/malloc.srd")));
 
-        match crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules) {
+        match crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules, &mut Defines::new()) {
             Ok(_) => {
-                assert!(false);
+                panic!("importing unknown module should fail");
             }
             Err(err) => {
-                assert_eq!("Failed to read /std/bad_name", err);
+                assert_eq!(err.summary(), "main:2:9: Failed to read /std/bad_name");
             }
         };
     }
-}
\ No newline at end of file
+}
+
+fn expand(source: &str) -> Result<Vec<String>, String> {
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    crate::macros::expand_macros(&SourceLine::from_lines(&lines, ""))
+        .map(|lines| lines.into_iter().map(|line| line.text).collect())
+        .map_err(|diagnostic| diagnostic.summary())
+}
+
+#[test]
+fn test_macros() {
+    {
+        let lines = expand("\
+            main:\n\
+                print 0x01, \"a, b\" ; comment\n\
+                print STDERR, (1, 2)\n\
+            #macro print fd, text\n\
+                push %fd\n\
+                push_addr %text ; %text isn't replaced in comments\n\
+                push '%'\n\
+            #endmacro\n\
+        ").unwrap();
+
+        assert_eq!(lines, vec![
+            "main:",
+            "push 0x01", "push_addr \"a, b\" ; %text isn't replaced in comments", "push '%'",
+            "push STDERR", "push_addr (1, 2) ; %text isn't replaced in comments", "push '%'",
+        ]);
+    }
+    {
+        // Local labels are unique for every expansion, macros can use other macros
+        let lines = expand("\
+            #macro wait\n\
+            %%loop:\n\
+                jump %%loop\n\
+            #endmacro\n\
+            #macro wait_twice\n\
+                wait\n\
+                wait\n\
+            #endmacro\n\
+            wait_twice\n\
+        ").unwrap();
+
+        assert_eq!(lines, vec![
+            ".wait_2_loop:", "jump .wait_2_loop",
+            ".wait_3_loop:", "jump .wait_3_loop",
+        ]);
+    }
+    {
+        // Assembler errors point at the macro body and note the invocation
+        let source = "\
+            main:\n\
+                bad_push 0x01\n\
+            #macro bad_push value\n\
+                push %value\n\
+                push asdasd\n\
+            #endmacro\n\
+            bad_push 0x02\n\
+        ";
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let expanded = crate::macros::expand_macros(&SourceLine::from_lines(&lines, "main.srd")).unwrap();
+        let diagnostics = shard_compiler::compile(&expanded).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].summary(), "main.srd:5:6: failed to parse value 'asdasd' - \
+            'asdasd' is not a constant, label addresses can only be used with lo() or hi()");
+        assert_eq!(diagnostics[0].notes, vec!["in expansion of macro 'bad_push' at main.srd:2"]);
+        assert_eq!(diagnostics[1].notes, vec!["in expansion of macro 'bad_push' at main.srd:7"]);
+    }
+    {
+        // Nested expansions note every invocation
+        let source = "#macro inner\n    jump missing\n#endmacro\n#macro outer\n    inner\n#endmacro\nouter\n";
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let expanded = crate::macros::expand_macros(&SourceLine::from_lines(&lines, "main.srd")).unwrap();
+        let diagnostics = shard_compiler::compile(&expanded).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].to_string(), [
+            "error: Unknown label 'missing'",
+            " --> main.srd:2:10",
+            "  |",
+            "2 |     jump missing",
+            "  |          ^^^^^^^",
+            "  = note: in expansion of macro 'inner' at main.srd:5",
+            "  = note: in expansion of macro 'outer' at main.srd:7",
+        ].join("\n"));
+    }
+    {
+        assert_eq!(expand("#macro one a\n#endmacro\none\n").unwrap_err(),
+                   "3: macro 'one' expects 1 argument(s), got 0");
+        assert_eq!(expand("#macro one\n#endmacro\n#macro one\n#endmacro\n").unwrap_err(),
+                   "3: macro 'one' is already defined at line 1");
+        assert_eq!(expand("#macro one\n    push %missing\n#endmacro\n\none\n").unwrap_err(),
+                   "2: unknown macro parameter '%missing'");
+        assert_eq!(expand("#macro loop\n    loop\n#endmacro\nloop\n").unwrap_err(),
+                   "2: macro 'loop' is expanded recursively");
+        assert!(expand("#macro open\n").is_err());
+        assert!(expand("#macro push\n#endmacro\n").is_err());
+        assert!(expand("#macro dup_param a, a\n#endmacro\n").is_err());
+        assert!(expand("#endmacro\n").is_err());
+    }
+}
+
+fn preprocess(source: &str, defines: &mut Defines) -> Result<Vec<String>, String> {
+    let lines: Vec<String> = source.lines().map(|line| String::from(line.trim())).collect();
+    let mut lines = SourceLine::from_lines(&lines, "");
+    match crate::conditionals::apply_conditionals(&mut lines, defines) {
+        Ok(()) => Ok(lines.into_iter().map(|line| line.text).collect()),
+        Err(diagnostic) => Err(diagnostic.summary()),
+    }
+}
+
+#[test]
+fn test_conditionals() {
+    {
+        let mut defines = Defines::new();
+        defines.insert(String::from("HAS_FILES"), Some(1));
+        defines.insert(String::from("VERSION"), Some(2));
+
+        let lines = preprocess("\
+            #ifdef HAS_FILES\n\
+                push 0x01\n\
+                #ifndef HAS_FILES\n\
+                    push 0x02\n\
+                #else\n\
+                    push 0x03\n\
+                #endif\n\
+            #else\n\
+                push 0x04\n\
+            #endif\n\
+            #if VERSION - 1\n\
+                push 0x05\n\
+            #endif\n\
+            #if UNDEFINED\n\
+                push 0x06\n\
+            #elif VERSION & 1\n\
+                push 0x07\n\
+            #elif VERSION >> 1\n\
+                push 0x08\n\
+            #elif 1\n\
+                push 0x09\n\
+            #endif\n\
+        ", &mut defines).unwrap();
+
+        let assembled: Vec<&String> = lines.iter().filter(|line| !line.is_empty()).collect();
+        assert_eq!(assembled, vec!["push 0x01", "push 0x03", "push 0x05", "push 0x08"]);
+        assert_eq!(lines.len(), 22);
+        assert_eq!(lines[1], "push 0x01");
+    }
+    {
+        // Defines from the source can be used in conditions and are kept for the assembler
+        let mut defines = Defines::new();
+
+        let lines = preprocess("\
+            #define FEATURE\n\
+            #define LEVEL FEATURE + 2\n\
+            .equ SIZE 0x10\n\
+            #define FROM_EQU SIZE\n\
+            #if LEVEL - 3\n\
+                #define SKIPPED\n\
+            #endif\n\
+        ", &mut defines).unwrap();
+
+        assert_eq!(lines[0], "#define FEATURE");
+        assert_eq!(lines[1], "#define LEVEL FEATURE + 2");
+        assert_eq!(lines[5], "");
+        assert_eq!(defines.get("FEATURE"), Some(&Some(1)));
+        assert_eq!(defines.get("LEVEL"), Some(&Some(3)));
+        assert_eq!(defines.get("FROM_EQU"), Some(&None));
+        assert!(!defines.contains_key("SKIPPED"));
+
+        let err = preprocess("#if FROM_EQU\n#endif\n", &mut defines).unwrap_err();
+        assert_eq!(err, "1: value of 'FROM_EQU' is not known to the preprocessor");
+    }
+    {
+        // Command line defines take precedence over #define in the source
+        let mut defines = Defines::new();
+        defines.insert(String::from("LEVEL"), Some(5));
+
+        let lines = preprocess("#define LEVEL 1\n#if LEVEL == 5\npush 0x01\n#endif\n", &mut defines).unwrap();
+        assert_eq!(lines[2], "push 0x01");
+        assert_eq!(defines.get("LEVEL"), Some(&Some(5)));
+    }
+    {
+        // Comparisons, logical operators and defined
+        let mut defines = Defines::new();
+        defines.insert(String::from("VERSION"), Some(2));
+        defines.insert(String::from("FROM_EQU"), None);
+
+        let condition = |source: &str, defines: &mut Defines| {
+            preprocess(&format!("#if {}\npush 0x01\n#endif\n", source), defines).map(|lines| lines[1] == "push 0x01")
+        };
+        assert_eq!(condition("VERSION == 2", &mut defines), Ok(true));
+        assert_eq!(condition("VERSION != 2", &mut defines), Ok(false));
+        assert_eq!(condition("VERSION < 3", &mut defines), Ok(true));
+        assert_eq!(condition("VERSION > 3", &mut defines), Ok(false));
+        assert_eq!(condition("VERSION <= 2", &mut defines), Ok(true));
+        assert_eq!(condition("VERSION >= 3", &mut defines), Ok(false));
+        assert_eq!(condition("!MISSING", &mut defines), Ok(true));
+        assert_eq!(condition("!VERSION", &mut defines), Ok(false));
+        assert_eq!(condition("VERSION && MISSING", &mut defines), Ok(false));
+        assert_eq!(condition("MISSING || VERSION == 2", &mut defines), Ok(true));
+        assert_eq!(condition("defined(VERSION)", &mut defines), Ok(true));
+        assert_eq!(condition("defined MISSING", &mut defines), Ok(false));
+        assert_eq!(condition("!defined(MISSING) && VERSION", &mut defines), Ok(true));
+        // Value doesn't need to be known to check whether it is defined
+        assert_eq!(condition("defined(FROM_EQU)", &mut defines), Ok(true));
+        assert_eq!(condition("UNDEFINED", &mut defines), Ok(false));
+
+        assert!(condition("defined(VERSION", &mut defines).is_err());
+        assert!(condition("defined", &mut defines).is_err());
+    }
+    {
+        // Imports of inactive branches are skipped
+        let main_module = String::from("#ifdef USE_MALLOC\n#import std/malloc\n#endif\n");
+        let module_name = String::from("main");
+        let mock_dir = String::from("");
+
+        let mut included_modules = HashSet::new();
+        let mut standard_modules = HashMap::new();
+        standard_modules.insert(String::from("std/malloc"), String::from(include_str!("../../standard_modules/std/malloc.srd")));
+
+        let lines = crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules, &mut Defines::new()).unwrap();
+        assert!(lines.iter().all(|line| !line.text.contains("malloc:")));
+
+        let mut defines = Defines::new();
+        defines.insert(String::from("USE_MALLOC"), Some(1));
+        let mut included_modules = HashSet::new();
+        let lines = crate::load_module_from_string(&main_module, &module_name, &mock_dir, &mut included_modules, &standard_modules, &mut defines).unwrap();
+        assert!(lines.iter().any(|line| line.text.contains("malloc:")));
+    }
+    {
+        let mut defines = Defines::new();
+
+        assert_eq!(preprocess("#else\n", &mut defines).unwrap_err(), "1: #else without #if");
+        assert_eq!(preprocess("#endif\n", &mut defines).unwrap_err(), "1: #endif without #if");
+        assert_eq!(preprocess("\n#ifdef A\n", &mut defines).unwrap_err(), "2: #ifdef is missing #endif");
+        assert_eq!(preprocess("#if 1\n#else\n#elif 1\n#endif\n", &mut defines).unwrap_err(), "3: #elif after #else of #if at line 1");
+        assert!(preprocess("#if 1 +\n#endif\n", &mut defines).is_err());
+        assert!(preprocess("#ifdef\n#endif\n", &mut defines).is_err());
+    }
+}
+
+#[test]
+fn test_module_interface() {
+    let mut lines = SourceLine::from_lines(&[String::from("#import std/io"), String::from("main:")], "main.srd");
+    lines.extend(SourceLine::from_lines(&[
+        String::from(".equ SIZE 2"),
+        String::from("#macro twice value"),
+        String::from("    push %value"),
+        String::from("    push %value"),
+        String::from("#endmacro"),
+        String::from("write:"),
+        String::from("    return"),
+        String::from("#define FLAG"),
+    ], "std/io"));
+
+    let lines: Vec<String> = crate::module_interface(lines, "main.srd").into_iter().map(|line| line.text).collect();
+    assert_eq!(lines, vec![
+        "#import std/io", "main:",
+        ".equ SIZE 2", "#macro twice value", "    push %value", "    push %value", "#endmacro", "#define FLAG",
+    ]);
+}
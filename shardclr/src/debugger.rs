@@ -0,0 +1,323 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+
+use std::io::{self, BufRead, Write};
+
+use shard_compiler::disassembler::{decode_instruction, format_instruction};
+use shard_compiler::lexer;
+use shard_compiler::symbols::{AddressNames, SymbolMap};
+use shard_vm::error::VmError;
+use shard_vm::vm::{VM, ExecutionStatus, InterruptHandler, InterruptType};
+
+// Instructions shown before and after pc by disasm
+const DISASM_CONTEXT: usize = 4;
+const DEFAULT_DUMP_SIZE: u16 = 64;
+const DUMP_LINE_SIZE: usize = 16;
+
+const HELP: &str = "\
+break <location>       stop before executing the instruction at label, address or file:line
+delete <location>      remove a breakpoint
+step                   execute one instruction
+next                   execute one instruction, calls are run until they return
+finish                 run until the current function returns
+continue               run until a breakpoint or the end of the program
+regs                   show registers
+stack                  show data stack and return addresses
+x <location> [size]    show memory starting at the location
+disasm                 show instructions around pc
+quit                   stop debugging
+Commands can be shortened to their first letter, empty line repeats the last one.";
+
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Exited,
+    OutOfFuel,
+    Fault(VmError),
+}
+
+// Label and source line of an address, e.g. "in main+0x3, main.srd:6"
+pub fn symbolic_location(symbols: &SymbolMap, address: u16) -> Option<String> {
+    let label = symbols.label_before(address).map(|label| match address - label.address {
+        0 => format!("in {}", label.name),
+        offset => format!("in {}+0x{:x}", label.name, offset),
+    });
+    let line = symbols.line_at(address).map(|mapping| mapping.to_string());
+    match (label, line) {
+        (Some(label), Some(line)) => Some(format!("{}, {}", label, line)),
+        (label, line) => label.or(line),
+    }
+}
+
+// Runs the program one command at a time. Syscalls and traps are passed to the interrupt handler.
+pub struct Debugger<'a> {
+    vm: VM,
+    interrupt_handler: &'a mut dyn InterruptHandler,
+    symbols: Option<SymbolMap>,
+    names: AddressNames,
+    // Instructions end here, the rest of the image is data
+    code_size: u16,
+    running: bool,
+}
+
+impl<'a> Debugger<'a> {
+    // Program starts at the VM's entry point
+    pub fn new(mut vm: VM, interrupt_handler: &'a mut dyn InterruptHandler, symbols: Option<SymbolMap>, code_size: u16) -> Debugger<'a> {
+        vm.reset();
+        let names = AddressNames::new(symbols.as_ref(), u16::MAX as usize + 1);
+        Debugger { vm, interrupt_handler, symbols, names, code_size, running: true }
+    }
+
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        self.write_position(output)?;
+        let mut last_command = String::new();
+        loop {
+            write!(output, "(shardclr) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = match line.trim() {
+                "" => last_command.clone(),
+                command => command.to_string(),
+            };
+            last_command = command.clone();
+
+            let args: Vec<&str> = command.split_whitespace().collect();
+            match args.first().copied() {
+                Some("quit") | Some("q") => return Ok(()),
+                Some(_) => self.execute_command(&args, output)?,
+                None => {}
+            }
+        }
+    }
+
+    fn execute_command(&mut self, args: &[&str], output: &mut dyn Write) -> io::Result<()> {
+        match args[0] {
+            "break" | "b" => match args.get(1).map(|location| self.parse_location(location)) {
+                Some(Ok(address)) => {
+                    self.vm.set_breakpoint(address);
+                    writeln!(output, "Breakpoint at {}", self.describe(address))
+                }
+                Some(Err(err)) => writeln!(output, "{}", err),
+                None => writeln!(output, "break expects a location"),
+            },
+            "delete" | "d" => match args.get(1).map(|location| self.parse_location(location)) {
+                Some(Ok(address)) if self.vm.remove_breakpoint(address) => writeln!(output, "Removed breakpoint at 0x{:04x}", address),
+                Some(Ok(address)) => writeln!(output, "No breakpoint at 0x{:04x}", address),
+                Some(Err(err)) => writeln!(output, "{}", err),
+                None => writeln!(output, "delete expects a location"),
+            },
+            "step" | "s" => self.resume(&|_, _| true, output),
+            // Call stack grows downwards, csp is back where it was once a call returns
+            "next" | "n" => {
+                let csp = self.vm.get_csp();
+                self.resume(&move |vm, _| vm.get_csp() >= csp, output)
+            }
+            "finish" | "f" => {
+                let csp = self.vm.get_csp();
+                self.resume(&move |vm, _| vm.get_csp() > csp, output)
+            }
+            "continue" | "c" => self.resume(&|_, at_breakpoint| at_breakpoint, output),
+            "regs" | "r" => {
+                let vm = &self.vm;
+                writeln!(output, "pc    0x{:04x}", vm.get_pc())?;
+                writeln!(output, "sp    0x{:02x}", vm.get_sp())?;
+                writeln!(output, "csp   0x{:02x}", vm.get_csp())?;
+                writeln!(output, "reg_a 0x{:02x}", vm.get_reg_a())?;
+                writeln!(output, "reg_b 0x{:02x}", vm.get_reg_b())
+            }
+            "stack" => {
+                let values: Vec<String> = self.vm.stack_values().iter().map(|value| format!("0x{:02x}", value)).collect();
+                match values.is_empty() {
+                    true => writeln!(output, "Data stack is empty")?,
+                    false => writeln!(output, "Data stack (top first): {}", values.join(" "))?,
+                }
+                for (depth, address) in self.vm.call_stack_addresses().iter().enumerate() {
+                    writeln!(output, "#{} returns to {}", depth, self.describe(*address))?;
+                }
+                Ok(())
+            }
+            "x" => {
+                let size = match args.get(2).map(|size| lexer::parse_value(size).and_then(lexer::value_to_u16)) {
+                    Some(Ok(size)) => size,
+                    Some(Err(err)) => return writeln!(output, "Invalid size - {}", err),
+                    None => DEFAULT_DUMP_SIZE,
+                };
+                match args.get(1).map(|location| self.parse_location(location)) {
+                    Some(Ok(address)) => self.write_memory(address, size, output),
+                    Some(Err(err)) => writeln!(output, "{}", err),
+                    None => writeln!(output, "x expects a location"),
+                }
+            }
+            "disasm" | "l" => self.write_disassembly(output),
+            "help" | "h" => writeln!(output, "{}", HELP),
+            command => writeln!(output, "Unknown command '{}', try 'help'", command),
+        }
+    }
+
+    // Executes instructions until done returns true, it gets the VM and whether pc is at a breakpoint
+    fn resume(&mut self, done: &dyn Fn(&VM, bool) -> bool, output: &mut dyn Write) -> io::Result<()> {
+        if !self.running {
+            return writeln!(output, "The program is not running");
+        }
+
+        let stop = loop {
+            let status = match self.vm.execute_instruction() {
+                Ok(status) => status,
+                Err(err) => break Stop::Fault(err),
+            };
+            let at_breakpoint = match status {
+                ExecutionStatus::Continue => false,
+                ExecutionStatus::Breakpoint => true,
+                ExecutionStatus::SysCall => match self.interrupt_handler.handle_interrupt(&mut self.vm, InterruptType::SysCall) {
+                    // Syscalls don't report breakpoints after them
+                    Ok(()) => self.vm.has_breakpoint(self.vm.get_pc()),
+                    Err(err) => break Stop::Fault(err),
+                },
+                ExecutionStatus::Trap(error) => match self.interrupt_handler.handle_interrupt(&mut self.vm, InterruptType::Trap(error)) {
+                    Ok(()) => self.vm.has_breakpoint(self.vm.get_pc()),
+                    Err(err) => break Stop::Fault(err),
+                },
+                ExecutionStatus::Done => break Stop::Exited,
+                ExecutionStatus::OutOfFuel => break Stop::OutOfFuel,
+            };
+            if done(&self.vm, at_breakpoint) {
+                break if at_breakpoint { Stop::Breakpoint } else { Stop::Stepped };
+            }
+            if at_breakpoint {
+                break Stop::Breakpoint;
+            }
+        };
+
+        match stop {
+            Stop::Stepped => self.write_position(output),
+            Stop::Breakpoint => {
+                write!(output, "Breakpoint, ")?;
+                self.write_position(output)
+            }
+            Stop::Exited => {
+                self.running = false;
+                writeln!(output, "Program exited")
+            }
+            Stop::OutOfFuel => {
+                self.running = false;
+                writeln!(output, "Out of fuel")
+            }
+            Stop::Fault(err) => {
+                self.running = false;
+                writeln!(output, "Program stopped with error:\n{}", err)?;
+                match err.pc().and_then(|pc| self.symbols.as_ref().and_then(|symbols| symbolic_location(symbols, pc))) {
+                    Some(location) => writeln!(output, "  {}", location),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    // Label, address or file:line of an instruction
+    fn parse_location(&self, location: &str) -> Result<u16, String> {
+        if let Ok(value) = lexer::parse_value(location) {
+            return lexer::value_to_u16(value).map_err(|err| format!("Invalid address '{}' - {}", location, err));
+        }
+
+        let symbols = match &self.symbols {
+            Some(symbols) => symbols,
+            None => return Err(format!("Can't find '{}' without a symbol file", location)),
+        };
+        if let Some((file, line)) = location.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?))) {
+            return symbols.lines.iter()
+                .find(|mapping| mapping.line == line && (mapping.file == file || mapping.file.ends_with(&format!("/{}", file))))
+                .map(|mapping| mapping.address)
+                .ok_or_else(|| format!("No code at {}", location));
+        }
+        symbols.get_address(location).ok_or_else(|| format!("Unknown label '{}'", location))
+    }
+
+    // "0x0007 in f+0x1, fault.srd:7"
+    fn describe(&self, address: u16) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbolic_location(symbols, address)) {
+            Some(location) => format!("0x{:04x} {}", address, location),
+            None => format!("0x{:04x}", address),
+        }
+    }
+
+    fn write_position(&self, output: &mut dyn Write) -> io::Result<()> {
+        let pc = self.vm.get_pc();
+        writeln!(output, "{}", self.describe(pc))?;
+        let code = self.code();
+        match decode_instruction(&code, pc) {
+            Some(instruction) => writeln!(output, "=> {:04x}  {}", pc, format_instruction(&instruction, &self.names)),
+            None => writeln!(output, "=> {:04x}  ; not an instruction", pc),
+        }
+    }
+
+    fn write_disassembly(&self, output: &mut dyn Write) -> io::Result<()> {
+        let code = self.code();
+        let pc = self.vm.get_pc();
+
+        // Instructions are decoded from the start of the code, bytes that aren't instructions take one byte
+        let mut instructions = vec![];
+        let mut address = 0;
+        while address < code.len() {
+            let instruction = decode_instruction(&code, address as u16);
+            instructions.push((address as u16, instruction.clone()));
+            address += instruction.map_or(1, |instruction| instruction.size());
+        }
+        let current = instructions.iter().rposition(|(address, _)| *address <= pc).unwrap_or(0);
+
+        let start = current.saturating_sub(DISASM_CONTEXT);
+        for (address, instruction) in instructions.iter().skip(start).take(DISASM_CONTEXT * 2 + 1) {
+            for symbol in self.names.names_at(*address) {
+                writeln!(output, "{}:", symbol.name)?;
+            }
+            let marker = if *address == pc { "=>" } else { "  " };
+            let text = match instruction {
+                Some(instruction) => format_instruction(instruction, &self.names),
+                None => format!("; unknown opcode 0x{:02x}", code[*address as usize]),
+            };
+            writeln!(output, "{} {:04x}  {}", marker, address, text)?;
+        }
+        Ok(())
+    }
+
+    fn code(&self) -> Vec<u8> {
+        match self.code_size.checked_sub(1) {
+            Some(last) => self.vm.dump_memory_range(0, last),
+            None => vec![],
+        }
+    }
+
+    // Dumps at most up to and including 0xffff
+    fn write_memory(&self, address: u16, size: u16, output: &mut dyn Write) -> io::Result<()> {
+        let bytes = match size.checked_sub(1) {
+            Some(last) => self.vm.dump_memory_range(address, address.saturating_add(last)),
+            None => vec![],
+        };
+        for (index, line) in bytes.chunks(DUMP_LINE_SIZE).enumerate() {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = line.iter().map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }).collect();
+            writeln!(output, "{:04x}  {:<width$}  {}", address as usize + index * DUMP_LINE_SIZE, hex.join(" "), text, width = DUMP_LINE_SIZE * 3 - 1)?;
+        }
+        Ok(())
+    }
+}
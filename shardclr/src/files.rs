@@ -0,0 +1,203 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub const MAX_DESCRIPTORS: usize = 64;
+
+pub const OPEN_READ: u8 = 0x01;
+pub const OPEN_WRITE: u8 = 0x02;
+pub const OPEN_CREATE: u8 = 0x04;
+pub const OPEN_TRUNCATE: u8 = 0x08;
+pub const OPEN_APPEND: u8 = 0x10;
+
+pub const SEEK_START: u8 = 0x00;
+pub const SEEK_CURRENT: u8 = 0x01;
+pub const SEEK_END: u8 = 0x02;
+
+// Status codes returned to the guest in reg_b
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SysError {
+    BadDescriptor = 0x01,
+    NotFound = 0x02,
+    PermissionDenied = 0x03,
+    InvalidArgument = 0x04,
+    TooManyOpenFiles = 0x05,
+    AlreadyExists = 0x06,
+    Io = 0x07,
+}
+
+impl From<io::Error> for SysError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::NotFound => SysError::NotFound,
+            ErrorKind::PermissionDenied => SysError::PermissionDenied,
+            ErrorKind::AlreadyExists => SysError::AlreadyExists,
+            ErrorKind::InvalidInput => SysError::InvalidArgument,
+            _ => SysError::Io,
+        }
+    }
+}
+
+enum Descriptor {
+    Input(Box<dyn Read>),
+    Output(Box<dyn Write>),
+    File { file: File, readable: bool, writable: bool },
+}
+
+// Open descriptors of a guest program. 0, 1 and 2 are stdin, stdout and stderr.
+// Files can only be opened inside the root directory.
+pub struct DescriptorTable {
+    descriptors: Vec<Option<Descriptor>>,
+    root: PathBuf,
+}
+
+impl DescriptorTable {
+    pub fn new(stdin: Box<dyn Read>, stdout: Box<dyn Write>, stderr: Box<dyn Write>, root: PathBuf) -> DescriptorTable {
+        DescriptorTable {
+            descriptors: vec![
+                Some(Descriptor::Input(stdin)),
+                Some(Descriptor::Output(stdout)),
+                Some(Descriptor::Output(stderr)),
+            ],
+            root,
+        }
+    }
+
+    pub fn open(&mut self, path: &str, flags: u8) -> Result<u8, SysError> {
+        let readable = flags & OPEN_READ != 0;
+        let writable = flags & (OPEN_WRITE | OPEN_APPEND) != 0;
+        if !readable && !writable {
+            return Err(SysError::InvalidArgument);
+        }
+
+        let fd = match self.descriptors.iter().position(|descriptor| descriptor.is_none()) {
+            Some(fd) => fd,
+            None if self.descriptors.len() < MAX_DESCRIPTORS => {
+                self.descriptors.push(None);
+                self.descriptors.len() - 1
+            }
+            None => return Err(SysError::TooManyOpenFiles),
+        };
+
+        let full_path = self.resolve_path(path)?;
+        let file = OpenOptions::new()
+            .read(readable)
+            .write(flags & OPEN_WRITE != 0)
+            .append(flags & OPEN_APPEND != 0)
+            .create(writable && flags & OPEN_CREATE != 0)
+            .truncate(flags & OPEN_TRUNCATE != 0)
+            .open(full_path)?;
+
+        self.descriptors[fd] = Some(Descriptor::File { file, readable, writable });
+        Ok(fd as u8)
+    }
+
+    pub fn close(&mut self, fd: u8) -> Result<(), SysError> {
+        match self.descriptors.get_mut(fd as usize) {
+            Some(descriptor) if descriptor.is_some() => {
+                *descriptor = None;
+                Ok(())
+            }
+            _ => Err(SysError::BadDescriptor),
+        }
+    }
+
+    pub fn read(&mut self, fd: u8, buffer: &mut [u8]) -> Result<usize, SysError> {
+        let reader: &mut dyn Read = match self.get_descriptor(fd)? {
+            Descriptor::Input(input) => input.as_mut(),
+            Descriptor::File { file, readable: true, .. } => file,
+            _ => return Err(SysError::BadDescriptor),
+        };
+
+        loop {
+            match reader.read(buffer) {
+                Ok(bytes_read) => return Ok(bytes_read),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    pub fn write(&mut self, fd: u8, data: &[u8]) -> Result<usize, SysError> {
+        let writer: &mut dyn Write = match self.get_descriptor(fd)? {
+            Descriptor::Output(output) => output.as_mut(),
+            Descriptor::File { file, writable: true, .. } => file,
+            _ => return Err(SysError::BadDescriptor),
+        };
+
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(data.len())
+    }
+
+    pub fn seek(&mut self, fd: u8, offset: i16, whence: u8) -> Result<u64, SysError> {
+        let position = match whence {
+            SEEK_START if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CURRENT => SeekFrom::Current(offset as i64),
+            SEEK_END => SeekFrom::End(offset as i64),
+            _ => return Err(SysError::InvalidArgument),
+        };
+
+        match self.get_descriptor(fd)? {
+            Descriptor::File { file, .. } => Ok(file.seek(position)?),
+            _ => Err(SysError::BadDescriptor),
+        }
+    }
+
+    fn get_descriptor(&mut self, fd: u8) -> Result<&mut Descriptor, SysError> {
+        match self.descriptors.get_mut(fd as usize) {
+            Some(Some(descriptor)) => Ok(descriptor),
+            _ => Err(SysError::BadDescriptor),
+        }
+    }
+
+    // Only plain relative paths are accepted. Directories on the way can be symlinks as long as
+    // they stay inside the root, the file itself can't be a symlink.
+    fn resolve_path(&self, path: &str) -> Result<PathBuf, SysError> {
+        let relative_path = Path::new(path);
+        if path.is_empty() || !relative_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(SysError::PermissionDenied);
+        }
+
+        let root = self.root.canonicalize()?;
+        let full_path = root.join(relative_path);
+
+        let parent = match full_path.parent() {
+            Some(parent) => parent.canonicalize()?,
+            None => return Err(SysError::InvalidArgument),
+        };
+        if !parent.starts_with(&root) {
+            return Err(SysError::PermissionDenied);
+        }
+        // A dangling symlink can't be canonicalized and creating the file would follow it
+        match full_path.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Err(SysError::PermissionDenied),
+            Ok(_) if !full_path.canonicalize()?.starts_with(&root) => return Err(SysError::PermissionDenied),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(full_path)
+    }
+}
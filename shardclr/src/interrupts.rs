@@ -19,43 +19,125 @@
 
 use num_enum::TryFromPrimitive;
 use std::convert::TryFrom;
-use shard_vm::vm::{VM, InterruptType};
+use shard_vm::error::VmError;
+use shard_vm::vm::{VM, InterruptType, InterruptHandler};
 
+use crate::files::{DescriptorTable, SysError};
 
-pub fn interrupt_handler(vm: &mut VM, interrupt_type: InterruptType) {
-    match interrupt_type {
-        InterruptType::SysCall => syscall_handler(vm),
-        InterruptType::Breakpoint => { },
-    }
-}
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 enum Syscall {
     Read = 0x00,
     Write = 0x01,
+    Open = 0x02,
+    Close = 0x03,
+    Seek = 0x04,
 }
 
-fn syscall_handler(vm: &mut VM) {
-    let syscall_id = vm.stack_pop().unwrap();
-    let syscall = Syscall::try_from(syscall_id).unwrap();
+// Status code in reg_b after a successful syscall. Failures set it to a SysError value.
+const STATUS_OK: u8 = 0x00;
+
+pub struct Runtime {
+    descriptors: DescriptorTable,
+}
+
+impl Runtime {
+    pub fn new(descriptors: DescriptorTable) -> Runtime {
+        Runtime { descriptors }
+    }
+
+    fn syscall_handler(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        let syscall_id = vm.stack_pop()?;
+        let syscall = match Syscall::try_from(syscall_id) {
+            Ok(syscall) => syscall,
+            Err(_) => return Err(VmError::Interrupt(format!("Unknown syscall {:#04x}", syscall_id))),
+        };
 
-    match syscall {
-        Syscall::Read => {
+        let result = match syscall {
+            // Params: fd, buffer_address, size
+            // Reads up to size bytes into the buffer and returns number of bytes read in reg_a.
+            // 0 means end of input.
+            Syscall::Read => {
+                let size = vm.stack_pop()?;
+                let buffer_address = vm.stack_pop_address()?;
+                let fd = vm.stack_pop()?;
 
-        },
-        Syscall::Write => {
-            let size = vm.stack_pop().unwrap();
-            let data_address = vm.stack_pop_address().unwrap();
-            let _output_index = vm.stack_pop().unwrap();
+                let mut buffer = vec![0u8; size as usize];
+                let result = self.descriptors.read(fd, &mut buffer);
+                if let Ok(bytes_read) = result {
+                    let memory = vm.get_memory_mut();
+                    for (offset, value) in buffer[..bytes_read].iter().enumerate() {
+                        memory.write_u8(buffer_address.wrapping_add(offset as u16), *value)?;
+                    }
+                }
+                result.map(|bytes_read| bytes_read as u8)
+            },
+            // Params: fd, data_address, size
+            // Returns number of bytes written in reg_a.
+            Syscall::Write => {
+                let size = vm.stack_pop()?;
+                let data_address = vm.stack_pop_address()?;
+                let fd = vm.stack_pop()?;
 
-            let mut data = vec![];
-            for offset in 0..size as u16 {
-                data.push(vm.peek_memory(data_address + offset).unwrap());
+                let mut data = vec![];
+                for offset in 0..size as u16 {
+                    data.push(vm.peek_memory(data_address.wrapping_add(offset))?);
+                }
+                self.descriptors.write(fd, &data).map(|bytes_written| bytes_written as u8)
+            },
+            // Params: path_address, path_size, flags
+            // Path is relative to the runtime's root directory. Returns fd in reg_a.
+            Syscall::Open => {
+                let flags = vm.stack_pop()?;
+                let path_size = vm.stack_pop()?;
+                let path_address = vm.stack_pop_address()?;
+
+                let mut path = vec![];
+                for offset in 0..path_size as u16 {
+                    path.push(vm.peek_memory(path_address.wrapping_add(offset))?);
+                }
+                match String::from_utf8(path) {
+                    Ok(path) => self.descriptors.open(&path, flags),
+                    Err(_) => Err(SysError::InvalidArgument),
+                }
+            },
+            // Params: fd
+            Syscall::Close => {
+                let fd = vm.stack_pop()?;
+                self.descriptors.close(fd).map(|_| 0)
+            },
+            // Params: fd, offset (signed 16-bit address), whence - 0 start, 1 current, 2 end
+            Syscall::Seek => {
+                let whence = vm.stack_pop()?;
+                let offset = vm.stack_pop_address()? as i16;
+                let fd = vm.stack_pop()?;
+                self.descriptors.seek(fd, offset, whence).map(|_| 0)
+            },
+        };
+
+        match result {
+            Ok(value) => {
+                vm.set_reg_a(value);
+                vm.set_reg_b(STATUS_OK);
             }
+            Err(err) => {
+                vm.set_reg_a(0);
+                vm.set_reg_b(err as u8);
+            }
+        }
 
-            // TODO: use output_index
-            print!("{}", String::from_utf8(data).unwrap());
-        },
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl InterruptHandler for Runtime {
+    fn handle_interrupt(&mut self, vm: &mut VM, interrupt_type: InterruptType) -> Result<(), VmError> {
+        match interrupt_type {
+            InterruptType::SysCall => self.syscall_handler(vm),
+            InterruptType::Breakpoint => Ok(()),
+            // Guest faults are fatal for the runtime
+            InterruptType::Trap(error) => Err(error),
+        }
+    }
+}
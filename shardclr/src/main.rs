@@ -17,32 +17,114 @@
 // along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
 //
 
+mod debugger;
+mod files;
 mod interrupts;
 
-use std::{env, path::Path, fs::File, io::{BufReader, Read}};
+#[cfg(test)]
+mod tests;
 
-use shard_vm::vm::VM;
+use std::{env, path::{Path, PathBuf}, fs::File, io::{self, BufReader, Read}};
+
+use shard_compiler::symbols::SymbolMap;
+use shard_vm::vm::{VM, ExitStatus};
+
+use crate::debugger::{Debugger, symbolic_location};
+use crate::files::DescriptorTable;
+use crate::interrupts::Runtime;
 
 
 fn print_help() {
-    println!("shardclr [binary_image_path]\nExample: shardclr image.bin");
+    println!("shardclr [options] [binary_image_path]\nExample: shardclr image.bin");
+    println!("Options:");
+    println!("  --fuel <amount>    stop after executing <amount> instructions");
+    println!("  --protect          enable memory protection");
+    println!("  --root <dir>       directory the program can open files in (default: current directory)");
+    println!("  --symbols <file>   symbol file written by shardc, errors show the label and source line");
+    println!("  --debug            run the program in the debugger, type 'help' for its commands");
+    println!("                     commands and the program's input share stdin, a read syscall gets");
+    println!("                     the lines that follow the command that resumed the program");
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 {
-        print_help();
-        return;
+    let mut binary_image_path = None;
+    let mut fuel = None;
+    let mut protect = false;
+    let mut root = PathBuf::from(".");
+    let mut symbol_file = None;
+    let mut debug = false;
+
+    let mut arg_it = args.iter().skip(1);
+    while let Some(arg) = arg_it.next() {
+        match arg.as_str() {
+            "--fuel" => {
+                match arg_it.next().map(|value| value.parse::<u64>()) {
+                    Some(Ok(value)) => fuel = Some(value),
+                    _ => {
+                        println!("--fuel expects a number");
+                        return;
+                    }
+                }
+            }
+            "--protect" => protect = true,
+            "--debug" => debug = true,
+            "--root" => {
+                match arg_it.next() {
+                    Some(value) => root = PathBuf::from(value),
+                    None => {
+                        println!("--root expects a directory");
+                        return;
+                    }
+                }
+            }
+            "--symbols" => {
+                match arg_it.next() {
+                    Some(value) => symbol_file = Some(value),
+                    None => {
+                        println!("--symbols expects a file");
+                        return;
+                    }
+                }
+            }
+            _ if arg.starts_with('-') => {
+                print_help();
+                return;
+            }
+            _ => binary_image_path = Some(arg),
+        }
     }
 
-    let binary_image_path = &args[1];
+    let binary_image_path = match binary_image_path {
+        Some(path) => path,
+        None => {
+            print_help();
+            return;
+        }
+    };
 
     if !Path::new(binary_image_path).exists() {
         println!("{} file doesn't exist", binary_image_path);
         return;
     }
 
+    let symbols = match symbol_file {
+        Some(file) => {
+            let symbols = std::fs::read_to_string(file)
+                .map_err(|_| format!("Failed to read {}", file))
+                .and_then(|text| SymbolMap::from_text(&text).map_err(|err| format!("{}:{}", file, err)));
+            match symbols {
+                Ok(symbols) => Some(symbols),
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
     let binary_image = {
         let file = File::open(binary_image_path).unwrap();
         let mut reader = BufReader::new(file);
@@ -51,9 +133,43 @@ fn main() {
         buffer
     };
 
-    let mut vm = VM::new(binary_image).unwrap();
+    // Images from older shardc versions have no header and start at 0x00. Without a header or symbols
+    // the whole image is treated as code.
+    let code_size = symbols.as_ref().map(|symbols| symbols.code_size);
+    let mut vm = match VM::from_image_with_options(&binary_image, code_size, protect) {
+        Ok(vm) => vm,
+        Err(err) => {
+            println!("shardclr error:\n{}", err);
+            return;
+        }
+    };
+    vm.set_fuel(fuel);
+
+    let descriptors = DescriptorTable::new(Box::new(io::stdin()), Box::new(io::stdout()), Box::new(io::stderr()), root);
+    let mut runtime = Runtime::new(descriptors);
+
+    if debug {
+        let code_size = vm.get_code_size();
+        let mut debugger = Debugger::new(vm, &mut runtime, symbols, code_size);
+        // Commands are read a byte at a time without holding the stdin lock, so the program's read
+        // syscalls can take over stdin between commands
+        if let Err(err) = debugger.run(&mut BufReader::with_capacity(1, io::stdin()), &mut io::stdout()) {
+            println!("shardclr error:\n{}", err);
+        }
+        return;
+    }
 
-    if let Err(err) = vm.execute(interrupts::interrupt_handler) {
-        println!("shardclr error:\n{}", err);
+    match vm.execute(&mut runtime) {
+        Ok(ExitStatus::Done) => {}
+        Ok(ExitStatus::OutOfFuel) => {
+            println!("shardclr error:\nOut of fuel");
+        }
+        Err(err) => {
+            println!("shardclr error:\n{}", err);
+            let location = err.pc().and_then(|pc| symbols.as_ref().and_then(|symbols| symbolic_location(symbols, pc)));
+            if let Some(location) = location {
+                println!("  {}", location);
+            }
+        }
     };
 }
@@ -0,0 +1,303 @@
+//
+// Copyright © 2020-2023  Egidijus Lileika
+//
+// This file is part of Shard Lang project
+//
+// Shard Lang is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Shard Lang is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with shard_lang. If not, see <https://www.gnu.org/licenses/>.
+//
+
+use std::{env, fs, io, path::{Path, PathBuf}, process};
+use shard_compiler::diagnostic::SourceLine;
+use shard_vm::vm::VM;
+use crate::debugger::Debugger;
+use crate::files::*;
+use crate::interrupts::Runtime;
+
+fn new_sandbox(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("shardclr_{}_{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn new_table(root: &Path) -> DescriptorTable {
+    DescriptorTable::new(Box::new(io::empty()), Box::new(io::sink()), Box::new(io::sink()), root.to_path_buf())
+}
+
+// Runs the program with the runtime and returns the VM for inspection
+fn run_program(asm_source: &[&str], runtime: &mut Runtime) -> VM {
+    let code = shard_compiler::compile_from_asm(asm_source.iter().map(|line| line.to_string()).collect()).unwrap();
+    let mut vm = VM::new(code).unwrap();
+    vm.execute(runtime).unwrap();
+    vm
+}
+
+#[test]
+fn test_descriptor_table() {
+    {
+        let root = new_sandbox("files");
+        let mut table = new_table(&root);
+
+        let fd = table.open("data.txt", OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(table.write(fd, b"hello").unwrap(), 5);
+        let mut buffer = [0u8; 4];
+        assert_eq!(table.read(fd, &mut buffer), Err(SysError::BadDescriptor));
+        table.close(fd).unwrap();
+        assert_eq!(table.close(fd), Err(SysError::BadDescriptor));
+
+        let fd = table.open("./data.txt", OPEN_READ).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(table.seek(fd, 1, SEEK_START).unwrap(), 1);
+        assert_eq!(table.read(fd, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"ello");
+        assert_eq!(table.read(fd, &mut buffer).unwrap(), 0);
+        assert_eq!(table.seek(fd, -2, SEEK_END).unwrap(), 3);
+        assert_eq!(table.seek(fd, -1, SEEK_START), Err(SysError::InvalidArgument));
+        assert_eq!(table.seek(fd, 0, 0x03), Err(SysError::InvalidArgument));
+        assert_eq!(table.write(fd, b"x"), Err(SysError::BadDescriptor));
+
+        let fd = table.open("data.txt", OPEN_APPEND).unwrap();
+        assert_eq!(fd, 4);
+        table.write(fd, b"!").unwrap();
+        assert_eq!(fs::read(root.join("data.txt")).unwrap(), b"hello!");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+    {
+        let root = new_sandbox("stdio");
+        let mut table = new_table(&root);
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(table.read(0, &mut buffer).unwrap(), 0);
+        assert_eq!(table.write(0, b"x"), Err(SysError::BadDescriptor));
+        assert_eq!(table.write(1, b"x").unwrap(), 1);
+        assert_eq!(table.write(2, b"x").unwrap(), 1);
+        assert_eq!(table.read(1, &mut buffer), Err(SysError::BadDescriptor));
+        assert_eq!(table.seek(1, 0, SEEK_START), Err(SysError::BadDescriptor));
+        assert_eq!(table.write(0xff, b"x"), Err(SysError::BadDescriptor));
+
+        table.close(1).unwrap();
+        assert_eq!(table.write(1, b"x"), Err(SysError::BadDescriptor));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+    {
+        let root = new_sandbox("errors");
+        let mut table = new_table(&root);
+
+        assert_eq!(table.open("missing.txt", OPEN_READ), Err(SysError::NotFound));
+        assert_eq!(table.open("missing.txt", OPEN_READ | OPEN_CREATE), Err(SysError::NotFound));
+        assert_eq!(table.open("data.txt", 0x00), Err(SysError::InvalidArgument));
+        assert_eq!(table.open("", OPEN_READ), Err(SysError::PermissionDenied));
+
+        fs::write(root.join("data.txt"), b"data").unwrap();
+        for _ in 3..MAX_DESCRIPTORS {
+            table.open("data.txt", OPEN_READ).unwrap();
+        }
+        assert_eq!(table.open("data.txt", OPEN_READ), Err(SysError::TooManyOpenFiles));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+    {
+        let root = new_sandbox("escape");
+        let outside = new_sandbox("escape_outside");
+        fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        let mut table = new_table(&root);
+
+        assert_eq!(table.open("../secret.txt", OPEN_READ), Err(SysError::PermissionDenied));
+        assert_eq!(table.open("dir/../../secret.txt", OPEN_READ), Err(SysError::PermissionDenied));
+        let absolute_path = outside.join("secret.txt");
+        assert_eq!(table.open(absolute_path.to_str().unwrap(), OPEN_READ), Err(SysError::PermissionDenied));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            assert_eq!(table.open("link/secret.txt", OPEN_READ), Err(SysError::PermissionDenied));
+            std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret_link.txt")).unwrap();
+            assert_eq!(table.open("secret_link.txt", OPEN_READ), Err(SysError::PermissionDenied));
+
+            // Creating through a dangling link would write outside the root
+            std::os::unix::fs::symlink(outside.join("created.txt"), root.join("dangling.txt")).unwrap();
+            assert_eq!(table.open("dangling.txt", OPEN_WRITE | OPEN_CREATE), Err(SysError::PermissionDenied));
+            assert!(!outside.join("created.txt").exists());
+
+            // Links to files inside the root are rejected too
+            fs::write(root.join("inside.txt"), b"inside").unwrap();
+            std::os::unix::fs::symlink(root.join("inside.txt"), root.join("inside_link.txt")).unwrap();
+            assert_eq!(table.open("inside_link.txt", OPEN_READ), Err(SysError::PermissionDenied));
+            assert!(table.open("inside.txt", OPEN_READ).is_ok());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
+
+#[test]
+fn test_read_syscall() {
+    let root = new_sandbox("read_syscall");
+    let stdin = io::Cursor::new(b"abc".to_vec());
+    let mut runtime = Runtime::new(DescriptorTable::new(Box::new(stdin), Box::new(io::sink()), Box::new(io::sink()), root.clone()));
+
+    // Params are pushed as fd, buffer_address, size
+    let mut vm = run_program(&[
+        "  push 0x00",
+        "  push_addr buffer",
+        "  push 0x04",
+        "  push 0x00",
+        "  sys",
+        "  return",
+        "buffer: 0x00 0x00 0x00 0x00 0xff",
+    ], &mut runtime);
+
+    // The buffer is the last global, right before the stack
+    let buffer_address = vm.get_memory_mut().stack_start_address() - 5;
+    assert_eq!(vm.dump_memory_range(buffer_address, buffer_address + 4), b"abc\x00\xff");
+    assert_eq!(vm.get_reg_a(), 3);
+    assert_eq!(vm.get_reg_b(), 0x00);
+    assert_eq!(vm.stack_values(), vec![]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+// Runs a program that opens the path with the flags and returns (reg_a, reg_b)
+fn open_syscall(path: &str, flags: u8, runtime: &mut Runtime) -> (u8, u8) {
+    let path_bytes: Vec<String> = path.bytes().map(|byte| format!("{:#04x}", byte)).collect();
+    let path_global = format!("path: {}", path_bytes.join(" "));
+    let path_size = format!("  push {:#04x}", path.len());
+    let flags = format!("  push {:#04x}", flags);
+    let vm = run_program(&["  push_addr path", &path_size, &flags, "  push 0x02", "  sys", "  return", &path_global], runtime);
+    (vm.get_reg_a(), vm.get_reg_b())
+}
+
+#[test]
+fn test_syscalls() {
+    let root = new_sandbox("syscalls");
+    let outside = new_sandbox("syscalls_outside");
+    fs::write(outside.join("secret.txt"), b"secret").unwrap();
+    let mut runtime = Runtime::new(new_table(&root));
+
+    // Open returns the fd in reg_a and the status in reg_b
+    assert_eq!(open_syscall("data.txt", OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE, &mut runtime), (0x03, 0x00));
+    assert_eq!(open_syscall("missing.txt", OPEN_READ, &mut runtime), (0x00, SysError::NotFound as u8));
+    assert_eq!(open_syscall("../syscalls_outside/secret.txt", OPEN_READ, &mut runtime), (0x00, SysError::PermissionDenied as u8));
+    assert_eq!(open_syscall("data.txt", 0x00, &mut runtime), (0x00, SysError::InvalidArgument as u8));
+
+    // Write: fd, data_address, size
+    let vm = run_program(&["  push 0x03", "  push_addr data", "  push 0x05", "  push 0x01", "  sys", "  return", "data: 0x68 0x65 0x6c 0x6c 0x6f"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x05, 0x00));
+    let vm = run_program(&["  push 0x09", "  push_addr data", "  push 0x05", "  push 0x01", "  sys", "  return", "data: 0x68 0x65 0x6c 0x6c 0x6f"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x00, SysError::BadDescriptor as u8));
+
+    // Close: fd
+    let vm = run_program(&["  push 0x03", "  push 0x03", "  sys", "  return"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x00, 0x00));
+    let vm = run_program(&["  push 0x03", "  push 0x03", "  sys", "  return"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x00, SysError::BadDescriptor as u8));
+    assert_eq!(fs::read(root.join("data.txt")).unwrap(), b"hello");
+
+    // Seek: fd, offset, whence. Then read the rest of the file.
+    assert_eq!(open_syscall("data.txt", OPEN_READ, &mut runtime), (0x03, 0x00));
+    let mut vm = run_program(&[
+        "  push 0x03",
+        "  push_addr 0x0001",
+        "  push 0x00",
+        "  push 0x04",
+        "  sys",
+        "  get_reg_b",
+        "  store8 seek_status",
+        "  push 0x03",
+        "  push_addr buffer",
+        "  push 0x04",
+        "  push 0x00",
+        "  sys",
+        "  return",
+        "seek_status: 0xff",
+        "buffer: 0x00 0x00 0x00 0x00",
+    ], &mut runtime);
+    let buffer_address = vm.get_memory_mut().stack_start_address() - 4;
+    assert_eq!(vm.peek_memory(buffer_address - 1), Ok(0x00));
+    assert_eq!(vm.dump_memory_range(buffer_address, buffer_address + 3), b"ello");
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x04, 0x00));
+    let vm = run_program(&["  push 0x03", "  push_addr 0x0000", "  push 0x03", "  push 0x04", "  sys", "  return"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x00, SysError::InvalidArgument as u8));
+    let vm = run_program(&["  push 0x01", "  push_addr buffer", "  push 0x04", "  push 0x00", "  sys", "  return", "buffer: 0x00"], &mut runtime);
+    assert_eq!((vm.get_reg_a(), vm.get_reg_b()), (0x00, SysError::BadDescriptor as u8));
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&outside).unwrap();
+}
+
+#[test]
+fn test_debugger() {
+    let source = SourceLine::from_lines(&[
+        String::from("main:"),
+        String::from("    push 0x01"),
+        String::from("    call double"),
+        String::from("    set_reg_a"),
+        String::from("    return"),
+        String::from("double:"),
+        String::from("    stack_get 0x00"),
+        String::from("    add"),
+        String::from("    return"),
+        String::from("value: 0x2a"),
+    ], "main.srd");
+    let (bin, symbols) = shard_compiler::compile_with_symbols(&source).unwrap();
+
+    let root = new_sandbox("debugger");
+    let mut runtime = Runtime::new(new_table(&root));
+    let mut debugger = Debugger::new(VM::new(bin).unwrap(), &mut runtime, Some(symbols.clone()), symbols.code_size);
+
+    let commands = ["break double", "continue", "stack", "next", "", "regs", "finish", "x value 2", "b main.srd:9", "delete double", "c", "c", "s"];
+    let mut input = io::Cursor::new(commands.join("\n"));
+    let mut output = vec![];
+    debugger.run(&mut input, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let output: Vec<&str> = output.split("(shardclr) ").collect();
+
+    assert_eq!(output, vec![
+        "0x0000 in main, main.srd:2\n=> 0000  push 0x01\n",
+        "Breakpoint at 0x0007 in double, main.srd:7\n",
+        "Breakpoint, 0x0007 in double, main.srd:7\n=> 0007  stack_get 0x00\n",
+        "Data stack (top first): 0x01\n#0 returns to 0x0005 in main+0x5, main.srd:4\n",
+        "0x0009 in double+0x2, main.srd:8\n=> 0009  add\n",
+        "0x000a in double+0x3, main.srd:9\n=> 000a  return\n",
+        "pc    0x000a\nsp    0xfe\ncsp   0xfd\nreg_a 0x00\nreg_b 0x00\n",
+        "0x0005 in main+0x5, main.srd:4\n=> 0005  set_reg_a\n",
+        "000b  2a 00                                            *.\n",
+        "Breakpoint at 0x000a in double+0x3, main.srd:9\n",
+        "Removed breakpoint at 0x0007\n",
+        "Program exited\n",
+        "The program is not running\n",
+        "The program is not running\n",
+        "",
+    ]);
+
+    // Dumps reach the last byte of memory and stop there
+    let mut debugger = Debugger::new(VM::new(vec![0x00]).unwrap(), &mut runtime, None, 1);
+    let mut input = io::Cursor::new("x 0xfffe 4\nx 0xffff 1");
+    let mut output = vec![];
+    debugger.run(&mut input, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let output: Vec<&str> = output.split("(shardclr) ").skip(1).collect();
+
+    assert_eq!(output, vec![
+        "fffe  00 00                                            ..\n",
+        "ffff  00                                               .\n",
+        "",
+    ]);
+
+    fs::remove_dir_all(&root).unwrap();
+}